@@ -1,16 +1,21 @@
-use std::fs;
+use std::fs::{self, File, FileType, Metadata};
 use std::io::{self, Write as _};
 use std::path::{Path, PathBuf};
-use std::process::Command;
+
+mod retention;
+mod snapshot;
 
 use anyhow::{Context as _, ensure};
-use clap::Parser;
-use time::OffsetDateTime;
-use time::macros::format_description;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use time::{OffsetDateTime, PrimitiveDateTime};
 
 use common::quote_path;
 
+use retention::RetentionPolicy;
+use snapshot::Snapshot;
+
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
 /// Copy directories and files by adding a suffix which depends on the current datetime.
 /// Tested on Linux.
 ///
@@ -18,34 +23,105 @@ use common::quote_path;
 /// `/path/to/directory` to `/path/to/directory_2022-12-13-14h15` and
 /// `/path/to/file` to `/path/to/file_2022-12-13-14h15`.
 ///
-/// `backup` follows command-line symlinks.
+/// `backup` follows command-line symlinks and copies the other symlinks as symlinks. It preserves
+/// the permissions and the modification times of the directories and files. It fails on FIFOs,
+/// sockets and devices, and then removes the partial copy.
+///
+/// `backup restore ...` always runs the `restore` subcommand, so back up a path named `restore`
+/// with `backup ./restore`.
 struct Cli {
+    #[command(flatten)]
+    options: Options,
     src_paths: Vec<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-fn main() -> anyhow::Result<()> {
-    let Cli { src_paths } = Cli::parse();
-    let now = OffsetDateTime::now_local().context("failed to determine the local offset")?;
-    work(src_paths, now)
+#[derive(Args, Clone, Copy, Default)]
+struct Options {
+    /// How to copy the files
+    #[arg(long, value_enum, default_value_t = Mode::Copy)]
+    mode: Mode,
+    /// After all the copies, remove the backups of each path which the policy does not keep, like
+    /// `last=3,daily=7,weekly=4,monthly=12`
+    #[arg(long, value_name = "POLICY")]
+    keep: Option<RetentionPolicy>,
+    /// Show the backups which `--keep` would remove, without copying nor removing anything
+    #[arg(long, requires = "keep")]
+    dry_run: bool,
 }
 
-fn work(src_paths: Vec<PathBuf>, now: OffsetDateTime) -> anyhow::Result<()> {
-    let dst_path_suffix = get_dst_path_suffix(now);
-    let copy_actions: Vec<_> = check_all_copies_seem_possible(src_paths, &dst_path_suffix)?;
-    copy_actions.into_iter().try_for_each(|CopyAction { src_path, dst_path, src_is_dir }| {
-        copy(&src_path, &dst_path, src_is_dir)?;
-        writeln!(io::stdout(), "Copied {} to {}.", quote_path(&src_path), quote_path(&dst_path))
-            .context("failed to write to stdout")
-    })
+#[derive(Clone, Copy, Default, ValueEnum)]
+enum Mode {
+    /// Copy the content of each file
+    #[default]
+    Copy,
+    /// Clone each file with copy-on-write if the filesystem supports it, or else copy it
+    Reflink,
+    /// Hard link each file to the same file in the most recent backup if the size, the
+    /// permissions and the modification time did not change, or else copy it
+    HardlinkTree,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Restore a path from one of its backups.
+    ///
+    /// Without `--at` nor `--latest-before`, list the backups of the path.
+    ///
+    /// Otherwise, the chosen backup is copied next to the path, with copy-on-write if the
+    /// filesystem supports it. Then the copy and the current version of the path are exchanged
+    /// atomically if possible, and the current version is renamed as a new backup.
+    ///
+    /// A backup whose copy was interrupted by a crash looks complete, so `--latest-before` may
+    /// choose it: check the restored path.
+    Restore {
+        path: PathBuf,
+        /// Datetime of the backup to restore, like `2022-12-13-14h15`
+        #[arg(long, value_name = "DATETIME", value_parser = snapshot::parse_datetime)]
+        #[arg(conflicts_with = "latest_before")]
+        at: Option<PrimitiveDateTime>,
+        /// Restore the most recent backup made before this datetime, like `2022-12-13-14h15`
+        #[arg(long, value_name = "DATETIME", value_parser = snapshot::parse_datetime)]
+        latest_before: Option<PrimitiveDateTime>,
+    },
 }
 
-fn get_dst_path_suffix(now: OffsetDateTime) -> String {
-    now.format(&format_description!("_[year]-[month]-[day]-[hour]h[minute]")).unwrap()
+fn main() -> anyhow::Result<()> {
+    let Cli { options, src_paths, command } = Cli::parse();
+    let now = OffsetDateTime::now_local().context("failed to determine the local offset")?;
+    match command {
+        None => work(src_paths, options, now),
+        Some(Command::Restore { path, at, latest_before }) => {
+            restore(&path, at, latest_before, now)
+        }
+    }
+}
+
+fn work(src_paths: Vec<PathBuf>, options: Options, now: OffsetDateTime) -> anyhow::Result<()> {
+    let Options { mode, keep, dry_run } = options;
+    let dst_path_suffix = snapshot::suffix(now);
+    let copy_actions: Vec<_> = check_all_copies_seem_possible(src_paths, &dst_path_suffix, mode)?;
+    if !dry_run {
+        for copy_action in &copy_actions {
+            copy(copy_action, mode)?;
+            let CopyAction { src_path, dst_path, .. } = copy_action;
+            writeln!(io::stdout(), "Copied {} to {}.", quote_path(src_path), quote_path(dst_path))
+                .context("failed to write to stdout")?;
+        }
+    }
+    if let Some(policy) = keep {
+        for copy_action in &copy_actions {
+            prune(copy_action, policy, now, dry_run)?;
+        }
+    }
+    Ok(())
 }
 
 fn check_all_copies_seem_possible(
     src_paths: Vec<PathBuf>,
     dst_path_suffix: &str,
+    mode: Mode,
 ) -> anyhow::Result<Vec<CopyAction>> {
     src_paths
         .into_iter()
@@ -66,39 +142,385 @@ fn check_all_copies_seem_possible(
                 "{} already exists",
                 quote_path(&dst_path)
             );
-            Ok(CopyAction { src_path, dst_path, src_is_dir: src_metadata.is_dir() })
+            let previous_path = match mode {
+                Mode::Copy | Mode::Reflink => None,
+                Mode::HardlinkTree => {
+                    snapshot::find(&src_path)?.pop().map(|snapshot| snapshot.path)
+                }
+            };
+            Ok(CopyAction { src_path, dst_path, src_is_dir: src_metadata.is_dir(), previous_path })
         })
         .collect()
 }
 
-fn copy(src_path: &Path, dst_path: &Path, src_is_dir: bool) -> anyhow::Result<()> {
-    (|| {
-        if src_is_dir {
-            // TODO: Make the code cross-plateform.
-            let status = Command::new("cp")
-                .args(["-rH", "--"])
-                .args([src_path, dst_path])
-                .status()
-                .context("failed to execute process")?;
-            ensure!(status.success(), "error status: {status}");
+/// Copy `src_path` to `dst_path`. If this fails, the partial copy is removed, so that it cannot
+/// be taken for a complete backup.
+fn copy(copy_action: &CopyAction, mode: Mode) -> anyhow::Result<()> {
+    let CopyAction { src_path, dst_path, src_is_dir, previous_path } = copy_action;
+    let previous_path = previous_path.as_deref();
+    let dst_existed = dst_path.symlink_metadata().is_ok();
+    let result = if *src_is_dir {
+        copy_dir(src_path, dst_path, mode, previous_path)
+    } else {
+        copy_file(src_path, dst_path, mode, previous_path)
+    };
+    let result = result.with_context(|| {
+        format!("failed to copy {} to {}", quote_path(src_path), quote_path(dst_path))
+    });
+    if let Err(error) = result {
+        if !dst_existed && dst_path.symlink_metadata().is_ok() {
+            if let Err(removal_error) = remove(dst_path) {
+                return Err(error.context(format!(
+                    "failed to remove the partial copy {} after this error: {removal_error:#}",
+                    quote_path(dst_path)
+                )));
+            }
+        }
+        return Err(error);
+    }
+    Ok(())
+}
+
+/// Copy a directory tree like `cp -rH`: if `src_path` is a symlink, it is followed, but the
+/// symlinks inside the tree are copied as symlinks.
+///
+/// `previous_path` is the same directory in the most recent backup, in the `hardlink-tree` mode.
+fn copy_dir(
+    src_path: &Path,
+    dst_path: &Path,
+    mode: Mode,
+    previous_path: Option<&Path>,
+) -> anyhow::Result<()> {
+    let src_metadata = fs::metadata(src_path)
+        .with_context(|| format!("failed to read metadata from {}", quote_path(src_path)))?;
+    fs::create_dir(dst_path)
+        .with_context(|| format!("failed to create the directory {}", quote_path(dst_path)))?;
+    let entries = fs::read_dir(src_path)
+        .with_context(|| format!("failed to read the directory {}", quote_path(src_path)))?;
+    for entry in entries {
+        let entry = entry
+            .with_context(|| format!("failed to read an entry of {}", quote_path(src_path)))?;
+        let src_child_path = entry.path();
+        let dst_child_path = dst_path.join(entry.file_name());
+        let previous_child_path = previous_path.map(|path| path.join(entry.file_name()));
+        let previous_child_path = previous_child_path.as_deref();
+        let file_type = entry.file_type().with_context(|| {
+            format!("failed to read the file type of {}", quote_path(&src_child_path))
+        })?;
+        if file_type.is_dir() {
+            copy_dir(&src_child_path, &dst_child_path, mode, previous_child_path)?;
+        } else if file_type.is_symlink() {
+            copy_symlink(&src_child_path, &dst_child_path)?;
         } else {
-            fs::copy(src_path, dst_path)?;
+            copy_file(&src_child_path, &dst_child_path, mode, previous_child_path)?;
+        }
+    }
+    // The permissions and the modification time are copied last because the directory must stay
+    // writable while it is filled and its modification time changes each time a child is created.
+    copy_permissions_and_modification_time(&src_metadata, dst_path)
+}
+
+/// Copy a file, following `src_path` if it is a symlink.
+///
+/// `previous_path` is the same file in the most recent backup, in the `hardlink-tree` mode.
+fn copy_file(
+    src_path: &Path,
+    dst_path: &Path,
+    mode: Mode,
+    previous_path: Option<&Path>,
+) -> anyhow::Result<()> {
+    let src_metadata = fs::metadata(src_path)
+        .with_context(|| format!("failed to read metadata from {}", quote_path(src_path)))?;
+    // Reading a FIFO could block forever and reading a device would copy its content.
+    ensure!(
+        !is_special_file(src_metadata.file_type()),
+        "{} is a FIFO, a socket or a device, so it cannot be copied",
+        quote_path(src_path)
+    );
+    if let Some(previous_path) = previous_path {
+        if is_unchanged(&src_metadata, previous_path) {
+            return fs::hard_link(previous_path, dst_path).with_context(|| {
+                format!(
+                    "failed to create the hard link {} to {}",
+                    quote_path(dst_path),
+                    quote_path(previous_path)
+                )
+            });
         }
-        anyhow::Ok(())
+    }
+    match mode {
+        Mode::Copy | Mode::HardlinkTree => fs::copy(src_path, dst_path).map(drop),
+        Mode::Reflink => reflink_copy::reflink_or_copy(src_path, dst_path).map(drop),
+    }
+    .with_context(|| {
+        format!("failed to copy the file {} to {}", quote_path(src_path), quote_path(dst_path))
+    })?;
+    copy_permissions_and_modification_time(&src_metadata, dst_path)
+}
+
+/// Tell if the file of the most recent backup can be shared with a hard link.
+///
+/// Like the quick check of rsync, the content is not compared: a file is unchanged if its size and
+/// its modification time did not change. Its permissions must not change either, because they are
+/// shared by the hard links.
+fn is_unchanged(src_metadata: &Metadata, previous_path: &Path) -> bool {
+    previous_path.symlink_metadata().is_ok_and(|previous_metadata| {
+        previous_metadata.is_file()
+            && previous_metadata.len() == src_metadata.len()
+            && previous_metadata.permissions() == src_metadata.permissions()
+            && matches!(
+                (previous_metadata.modified(), src_metadata.modified()),
+                (Ok(previous_time), Ok(src_time)) if previous_time == src_time
+            )
+    })
+}
+
+fn copy_symlink(src_path: &Path, dst_path: &Path) -> anyhow::Result<()> {
+    let target = fs::read_link(src_path)
+        .with_context(|| format!("failed to read the symlink {}", quote_path(src_path)))?;
+    create_symlink(src_path, &target, dst_path).with_context(|| {
+        format!("failed to create the symlink {} to {}", quote_path(dst_path), quote_path(&target))
+    })
+    // Remark: the standard library cannot change the modification time of a symlink itself.
+}
+
+#[cfg(unix)]
+fn is_special_file(file_type: FileType) -> bool {
+    use std::os::unix::fs::FileTypeExt as _;
+
+    file_type.is_fifo()
+        || file_type.is_socket()
+        || file_type.is_block_device()
+        || file_type.is_char_device()
+}
+
+#[cfg(not(unix))]
+const fn is_special_file(_file_type: FileType) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn create_symlink(_src_path: &Path, target: &Path, dst_path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, dst_path)
+}
+
+#[cfg(windows)]
+fn create_symlink(src_path: &Path, target: &Path, dst_path: &Path) -> io::Result<()> {
+    // On Windows, a symlink to a directory differs from a symlink to a file.
+    if fs::metadata(src_path).is_ok_and(|metadata| metadata.is_dir()) {
+        std::os::windows::fs::symlink_dir(target, dst_path)
+    } else {
+        std::os::windows::fs::symlink_file(target, dst_path)
+    }
+}
+
+fn copy_permissions_and_modification_time(
+    src_metadata: &Metadata,
+    dst_path: &Path,
+) -> anyhow::Result<()> {
+    (|| {
+        let modification_time = src_metadata.modified()?;
+        // Remark: on Linux, a file opened in read-only mode can still get a new modification time.
+        File::open(dst_path)?.set_modified(modification_time)?;
+        fs::set_permissions(dst_path, src_metadata.permissions())
     })()
-    .with_context(|| format!("failed to copy {} to {}", quote_path(src_path), quote_path(dst_path)))
+    .with_context(|| {
+        format!(
+            "failed to copy the permissions and the modification time to {}",
+            quote_path(dst_path)
+        )
+    })
+}
+
+/// Remove the backups of the source path which `policy` does not keep, or only show them if
+/// `dry_run`.
+///
+/// The backups are assumed to be complete: a failed copy removes its partial copy, so only a copy
+/// interrupted by a crash or a kill can leave one.
+fn prune(
+    copy_action: &CopyAction,
+    policy: RetentionPolicy,
+    now: OffsetDateTime,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    let mut snapshots = snapshot::find(&copy_action.src_path)?;
+    if dry_run {
+        // The new backup has not been made, but the policy must take it into account.
+        let datetime = PrimitiveDateTime::new(now.date(), now.time());
+        snapshots.push(Snapshot { path: copy_action.dst_path.clone(), datetime });
+        snapshots.sort_by_key(|snapshot| snapshot.datetime);
+    }
+    let datetimes: Vec<_> = snapshots.iter().map(|snapshot| snapshot.datetime).collect();
+    for (snapshot, is_kept) in snapshots.iter().zip(policy.keep(&datetimes)) {
+        if is_kept {
+            continue;
+        }
+        if dry_run {
+            writeln!(io::stdout(), "Would remove {}.", quote_path(&snapshot.path))
+        } else {
+            remove(&snapshot.path)?;
+            writeln!(io::stdout(), "Removed {}.", quote_path(&snapshot.path))
+        }
+        .context("failed to write to stdout")?;
+    }
+    Ok(())
+}
+
+fn remove(path: &Path) -> anyhow::Result<()> {
+    let metadata = path
+        .symlink_metadata()
+        .with_context(|| format!("failed to read metadata from {}", quote_path(path)))?;
+    if metadata.is_dir() { fs::remove_dir_all(path) } else { fs::remove_file(path) }
+        .with_context(|| format!("failed to remove {}", quote_path(path)))
+}
+
+fn restore(
+    path: &Path,
+    at: Option<PrimitiveDateTime>,
+    latest_before: Option<PrimitiveDateTime>,
+    now: OffsetDateTime,
+) -> anyhow::Result<()> {
+    let snapshots = snapshot::find(path)?;
+    let snapshot = match (at, latest_before) {
+        (Some(at), _) => {
+            snapshots.iter().find(|snapshot| snapshot.datetime == at).with_context(|| {
+                let at = snapshot::format_datetime(at);
+                format!("{} has no backup made at {at}", quote_path(path))
+            })?
+        }
+        (None, Some(limit)) => {
+            snapshots.iter().rev().find(|snapshot| snapshot.datetime < limit).with_context(
+                || {
+                    let limit = snapshot::format_datetime(limit);
+                    format!("{} has no backup made before {limit}", quote_path(path))
+                },
+            )?
+        }
+        (None, None) => {
+            for snapshot in &snapshots {
+                writeln!(io::stdout(), "{}", quote_path(&snapshot.path))
+                    .context("failed to write to stdout")?;
+            }
+            return Ok(());
+        }
+    };
+    swap_in(path, &snapshot.path, now)
+}
+
+/// Replace `path` with a copy of `snapshot_path` and move the previous version aside as a new
+/// backup.
+///
+/// The copy is made before, under a temporary name, then exchanged atomically with `path` if the
+/// platform and the filesystem support it. Otherwise, `path` is missing between two renamings.
+fn swap_in(path: &Path, snapshot_path: &Path, now: OffsetDateTime) -> anyhow::Result<()> {
+    let file_name =
+        path.file_name().with_context(|| format!("{} does not have a name", quote_path(path)))?;
+    let path_metadata = path.symlink_metadata().ok();
+    ensure!(
+        !path_metadata.as_ref().is_some_and(Metadata::is_symlink),
+        "{} is a symlink, so restore the path it points to instead",
+        quote_path(path)
+    );
+    let aside_path = {
+        let mut aside_file_name = file_name.to_owned();
+        aside_file_name.push(snapshot::suffix(now));
+        path.with_file_name(aside_file_name)
+    };
+    let restoring_path = {
+        let mut restoring_file_name = file_name.to_owned();
+        restoring_file_name.push("_restoring");
+        path.with_file_name(restoring_file_name)
+    };
+    for new_path in [&aside_path, &restoring_path] {
+        ensure!(new_path.symlink_metadata().is_err(), "{} already exists", quote_path(new_path));
+    }
+    let snapshot_metadata = fs::metadata(snapshot_path)
+        .with_context(|| format!("failed to read metadata from {}", quote_path(snapshot_path)))?;
+    let copy_action = CopyAction {
+        src_path: snapshot_path.to_owned(),
+        dst_path: restoring_path,
+        src_is_dir: snapshot_metadata.is_dir(),
+        previous_path: None,
+    };
+    copy(&copy_action, Mode::Reflink)?;
+    let restoring_path = &copy_action.dst_path;
+    if path_metadata.is_none() {
+        fs::rename(restoring_path, path).with_context(|| {
+            format!("failed to move {} to {}", quote_path(restoring_path), quote_path(path))
+        })?;
+    } else {
+        if exchange(restoring_path, path).is_ok() {
+            // Now, `restoring_path` is the previous version.
+            fs::rename(restoring_path, &aside_path).with_context(|| {
+                format!(
+                    "failed to move the previous version {} to {}",
+                    quote_path(restoring_path),
+                    quote_path(&aside_path)
+                )
+            })?;
+        } else {
+            swap_by_renaming(path, restoring_path, &aside_path)?;
+        }
+        writeln!(io::stdout(), "Moved {} to {}.", quote_path(path), quote_path(&aside_path))
+            .context("failed to write to stdout")?;
+    }
+    writeln!(io::stdout(), "Restored {} from {}.", quote_path(path), quote_path(snapshot_path))
+        .context("failed to write to stdout")
+}
+
+/// Exchange two paths atomically, if the platform and the filesystem support it.
+#[cfg(target_os = "linux")]
+fn exchange(path: &Path, other_path: &Path) -> io::Result<()> {
+    use rustix::fs::{CWD, RenameFlags, renameat_with};
+
+    renameat_with(CWD, path, CWD, other_path, RenameFlags::EXCHANGE)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn exchange(_path: &Path, _other_path: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Move `path` to `aside_path`, then `new_path` to `path`. If the second renaming fails, move
+/// `aside_path` back to `path`.
+fn swap_by_renaming(path: &Path, new_path: &Path, aside_path: &Path) -> anyhow::Result<()> {
+    fs::rename(path, aside_path).with_context(|| {
+        format!("failed to move {} to {}", quote_path(path), quote_path(aside_path))
+    })?;
+    let result = fs::rename(new_path, path).with_context(|| {
+        format!("failed to move {} to {}", quote_path(new_path), quote_path(path))
+    });
+    if let Err(error) = result {
+        if let Err(rollback_error) = fs::rename(aside_path, path) {
+            return Err(error.context(format!(
+                "failed to move {} back to {} after this error: {rollback_error}",
+                quote_path(aside_path),
+                quote_path(path)
+            )));
+        }
+        return Err(error);
+    }
+    Ok(())
 }
 
 struct CopyAction {
     src_path: PathBuf,
     dst_path: PathBuf,
     src_is_dir: bool,
+    /// Most recent backup of `src_path`, in the `hardlink-tree` mode
+    previous_path: Option<PathBuf>,
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use std::fs::Permissions;
+    use std::os::unix::fs::{MetadataExt as _, PermissionsExt as _};
+    use std::process::Command;
+    use std::time::{Duration, SystemTime};
+
     use assert_fs::TempDir;
     use assert_fs::fixture::{
         FileWriteStr as _, PathChild as _, PathCreateDir as _, SymlinkToDir as _,
@@ -248,6 +670,311 @@ mod tests {
         temp.child("picture_2022-12-13-14h15").check_is_file_with_content("massive")
     }
 
+    #[test]
+    fn preserve_permissions_and_modification_times() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        // .
+        // └── colors/ (read-only)
+        //    ├── dark/
+        //    │  └── black (read-only)
+        //    └── red
+        temp.child("colors/dark/black").write_str("ink")?;
+        temp.child("colors/red").write_str("blood")?;
+        let old_time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        for (path, mode) in
+            [("colors/dark/black", 0o444), ("colors/dark", 0o750), ("colors", 0o555)]
+        {
+            File::open(temp.child(path))?.set_modified(old_time)?;
+            fs::set_permissions(temp.child(path), Permissions::from_mode(mode))?;
+        }
+        launch_work(&temp, ["colors"], datetime!(2022-12-13 14:15:16 UTC))?;
+        for (path, mode) in [
+            ("colors_2022-12-13-14h15/dark/black", 0o444),
+            ("colors_2022-12-13-14h15/dark", 0o750),
+            ("colors_2022-12-13-14h15", 0o555),
+        ] {
+            let metadata = fs::metadata(temp.child(path))?;
+            ensure!(metadata.permissions().mode() & 0o777 == mode, "wrong mode for {path}");
+            ensure!(metadata.modified()? == old_time, "wrong modification time for {path}");
+        }
+        temp.child("colors_2022-12-13-14h15/red").check_is_file_with_content("blood")?;
+        // Make the directories writable again so that `TempDir` can remove them.
+        for path in ["colors", "colors_2022-12-13-14h15"] {
+            fs::set_permissions(temp.child(path), Permissions::from_mode(0o755))?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn reflink_or_copy() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        temp.child("colors/dark/black").write_str("ink")?;
+        temp.child("picture").write_str("photo")?;
+        let options = Options { mode: Mode::Reflink, ..Options::default() };
+        launch_work_with(
+            &temp,
+            options,
+            ["colors", "picture"],
+            datetime!(2022-12-13 14:15:16 UTC),
+        )?;
+        temp.child("colors_2022-12-13-14h15/dark/black").check_is_file_with_content("ink")?;
+        temp.child("picture_2022-12-13-14h15").check_is_file_with_content("photo")
+    }
+
+    #[test]
+    fn hard_link_unchanged_files_to_the_most_recent_backup() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        // Before:
+        // .
+        // ├── colors/
+        // │  ├── dark/
+        // │  │  └── black
+        // │  └── red
+        // ├── colors_2022-12-12-10h00/
+        // ├── picture
+        // └── picture_2021-01-01-00h00 (older than the next backup)
+        temp.child("colors/dark/black").write_str("ink")?;
+        temp.child("colors/red").write_str("blood")?;
+        temp.child("colors_2022-12-12-10h00").create_dir_all()?;
+        temp.child("picture").write_str("photo")?;
+        temp.child("picture_2021-01-01-00h00").write_str("photo")?;
+        let options = Options { mode: Mode::HardlinkTree, ..Options::default() };
+        launch_work_with(
+            &temp,
+            options,
+            ["colors", "picture"],
+            datetime!(2022-12-13 14:15:16 UTC),
+        )?;
+        temp.child("colors/red").write_str("wine and blood")?;
+        temp.child("colors/white").write_str("snow")?;
+        launch_work_with(
+            &temp,
+            options,
+            ["colors", "picture"],
+            datetime!(2022-12-14 14:15:16 UTC),
+        )?;
+        // After:
+        // .
+        // ├── colors/
+        // │  ├── dark/
+        // │  │  └── black
+        // │  ├── red (changed)
+        // │  └── white (new)
+        // ├── colors_2022-12-12-10h00/
+        // ├── colors_2022-12-13-14h15/
+        // │  ├── dark/
+        // │  │  └── black
+        // │  └── red
+        // ├── colors_2022-12-14-14h15/
+        // │  ├── dark/
+        // │  │  └── black (hard link to colors_2022-12-13-14h15/dark/black)
+        // │  ├── red
+        // │  └── white
+        // ├── picture
+        // ├── picture_2021-01-01-00h00
+        // ├── picture_2022-12-13-14h15
+        // └── picture_2022-12-14-14h15 (hard link to picture_2022-12-13-14h15)
+        let inode = |path: &str| anyhow::Ok(fs::metadata(temp.child(path))?.ino());
+        let new_black = inode("colors_2022-12-14-14h15/dark/black")?;
+        ensure!(new_black == inode("colors_2022-12-13-14h15/dark/black")?);
+        ensure!(inode("colors_2022-12-14-14h15/red")? != inode("colors_2022-12-13-14h15/red")?);
+        ensure!(inode("picture_2022-12-14-14h15")? == inode("picture_2022-12-13-14h15")?);
+        ensure!(inode("picture_2022-12-13-14h15")? != inode("picture_2021-01-01-00h00")?);
+        temp.child("colors_2022-12-14-14h15/dark/black").check_is_file_with_content("ink")?;
+        temp.child("colors_2022-12-14-14h15/red").check_is_file_with_content("wine and blood")?;
+        temp.child("colors_2022-12-14-14h15/white").check_is_file_with_content("snow")?;
+        temp.child("colors_2022-12-13-14h15/red").check_is_file_with_content("blood")?;
+        temp.child("picture_2022-12-14-14h15").check_is_file_with_content("photo")
+    }
+
+    #[test]
+    fn remove_the_backups_which_the_policy_does_not_keep() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        // Before:
+        // .
+        // ├── colors/
+        // ├── colors_2022-12-11-10h00/
+        // ├── colors_2022-12-12-10h00/
+        // ├── colors_2022-12-12-18h00
+        // ├── colors_old/
+        // └── picture
+        temp.child("colors").create_dir_all()?;
+        temp.child("colors_2022-12-11-10h00/dark/black").write_str("ink")?;
+        temp.child("colors_2022-12-12-10h00").create_dir_all()?;
+        temp.child("colors_2022-12-12-18h00").write_str("whatever")?;
+        temp.child("colors_old").create_dir_all()?;
+        temp.child("picture").write_str("photo")?;
+        let keep = Some("last=2".parse()?);
+        let options = Options { keep, ..Options::default() };
+        launch_work_with(
+            &temp,
+            options,
+            ["colors", "picture"],
+            datetime!(2022-12-13 14:15:16 UTC),
+        )?;
+        // After:
+        // .
+        // ├── colors/
+        // ├── colors_2022-12-12-18h00
+        // ├── colors_2022-12-13-14h15/
+        // ├── colors_old/
+        // ├── picture
+        // └── picture_2022-12-13-14h15
+        temp.child("colors_2022-12-11-10h00").check_does_not_exist()?;
+        temp.child("colors_2022-12-12-10h00").check_does_not_exist()?;
+        temp.child("colors_2022-12-12-18h00").check_is_file_with_content("whatever")?;
+        temp.child("colors_2022-12-13-14h15").check_is_dir()?;
+        temp.child("colors_old").check_is_dir()?;
+        temp.child("picture_2022-12-13-14h15").check_is_file_with_content("photo")
+    }
+
+    #[test]
+    fn remove_no_backup_if_a_copy_fails() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        // .
+        // ├── colors/
+        // │  ├── pipe (FIFO)
+        // │  └── red
+        // ├── colors_2022-12-11-10h00/
+        // └── colors_2022-12-12-10h00/
+        temp.child("colors/red").write_str("blood")?;
+        let status = Command::new("mkfifo").arg(temp.child("colors/pipe").path()).status()?;
+        ensure!(status.success(), "mkfifo failed with {status}");
+        temp.child("colors_2022-12-11-10h00/red").write_str("blood")?;
+        temp.child("colors_2022-12-12-10h00/red").write_str("blood")?;
+        let options = Options { keep: Some("last=1".parse()?), ..Options::default() };
+        let result =
+            launch_work_with(&temp, options, ["colors"], datetime!(2022-12-13 14:15:16 UTC));
+        check_err_contains(result, "is a FIFO, a socket or a device, so it cannot be copied")?;
+        // Neither the partial copy remains nor the complete backups are pruned.
+        temp.child("colors_2022-12-11-10h00/red").check_is_file_with_content("blood")?;
+        temp.child("colors_2022-12-12-10h00/red").check_is_file_with_content("blood")?;
+        temp.child("colors_2022-12-13-14h15").check_does_not_exist()
+    }
+
+    #[test]
+    fn neither_copy_nor_remove_in_a_dry_run() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        temp.child("colors").create_dir_all()?;
+        temp.child("colors_2022-12-12-10h00").create_dir_all()?;
+        let options =
+            Options { keep: Some("last=1".parse()?), dry_run: true, ..Options::default() };
+        launch_work_with(&temp, options, ["colors"], datetime!(2022-12-13 14:15:16 UTC))?;
+        temp.child("colors_2022-12-12-10h00").check_is_dir()?;
+        temp.child("colors_2022-12-13-14h15").check_does_not_exist()
+    }
+
+    #[test]
+    fn restore_the_backup_made_at_a_datetime() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        // Before:
+        // .
+        // ├── colors/
+        // │  └── red
+        // ├── colors_2022-12-12-10h00/
+        // │  └── red
+        // └── colors_2022-12-13-10h00/
+        //    └── red
+        temp.child("colors/red").write_str("wine")?;
+        temp.child("colors_2022-12-12-10h00/red").write_str("blood")?;
+        temp.child("colors_2022-12-13-10h00/red").write_str("cherry")?;
+        let path = temp.child("colors");
+        let at = Some(datetime!(2022-12-12 10:00));
+        restore(&path, at, None, datetime!(2022-12-14 14:15:16 UTC))?;
+        // After:
+        // .
+        // ├── colors/
+        // │  └── red (restored from colors_2022-12-12-10h00)
+        // ├── colors_2022-12-12-10h00/
+        // │  └── red
+        // ├── colors_2022-12-13-10h00/
+        // │  └── red
+        // └── colors_2022-12-14-14h15/
+        //    └── red (current version before the restoration)
+        temp.child("colors/red").check_is_file_with_content("blood")?;
+        temp.child("colors_2022-12-12-10h00/red").check_is_file_with_content("blood")?;
+        temp.child("colors_2022-12-14-14h15/red").check_is_file_with_content("wine")?;
+        temp.child("colors_restoring").check_does_not_exist()
+    }
+
+    #[test]
+    fn restore_the_latest_backup_made_before_a_datetime() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        temp.child("picture").write_str("drawing")?;
+        temp.child("picture_2022-12-12-10h00").write_str("photo")?;
+        temp.child("picture_2022-12-13-10h00").write_str("painting")?;
+        let path = temp.child("picture");
+        let latest_before = Some(datetime!(2022-12-13 10:00));
+        restore(&path, None, latest_before, datetime!(2022-12-14 14:15:16 UTC))?;
+        temp.child("picture").check_is_file_with_content("photo")?;
+        temp.child("picture_2022-12-14-14h15").check_is_file_with_content("drawing")
+    }
+
+    #[test]
+    fn remove_the_partial_copy_if_a_restoration_fails() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        temp.child("colors/red").write_str("wine")?;
+        temp.child("colors_2022-12-12-10h00/red").write_str("blood")?;
+        let fifo_path = temp.child("colors_2022-12-12-10h00/pipe");
+        let status = Command::new("mkfifo").arg(fifo_path.path()).status()?;
+        ensure!(status.success(), "mkfifo failed with {status}");
+        let path = temp.child("colors");
+        let at = Some(datetime!(2022-12-12 10:00));
+        for _ in 0..2 {
+            // The second attempt does not fail because `colors_restoring` already exists.
+            let result = restore(&path, at, None, datetime!(2022-12-14 14:15:16 UTC));
+            check_err_contains(result, "is a FIFO, a socket or a device, so it cannot be copied")?;
+            temp.child("colors_restoring").check_does_not_exist()?;
+        }
+        temp.child("colors/red").check_is_file_with_content("wine")?;
+        temp.child("colors_2022-12-14-14h15").check_does_not_exist()
+    }
+
+    #[test]
+    fn move_the_current_version_back_if_the_second_renaming_fails() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        temp.child("picture").write_str("drawing")?;
+        let result = swap_by_renaming(
+            &temp.child("picture"),
+            &temp.child("picture_restoring"),
+            &temp.child("picture_2022-12-14-14h15"),
+        );
+        check_err_contains(result, "failed to move")?;
+        temp.child("picture").check_is_file_with_content("drawing")?;
+        temp.child("picture_2022-12-14-14h15").check_does_not_exist()
+    }
+
+    #[test]
+    fn fail_to_restore_a_missing_backup() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        temp.child("picture").write_str("drawing")?;
+        temp.child("picture_2022-12-12-10h00").write_str("photo")?;
+        let path = temp.child("picture");
+        let now = datetime!(2022-12-14 14:15:16 UTC);
+        let result = restore(&path, Some(datetime!(2022-12-12 11:00)), None, now);
+        check_err_contains(result, "has no backup made at 2022-12-12-11h00")?;
+        let result = restore(&path, None, Some(datetime!(2022-12-12 10:00)), now);
+        check_err_contains(result, "has no backup made before 2022-12-12-10h00")?;
+        temp.child("picture").check_is_file_with_content("drawing")?;
+        temp.child("picture_2022-12-14-14h15").check_does_not_exist()
+    }
+
+    #[test]
+    fn fail_to_copy_a_fifo() -> anyhow::Result<()> {
+        let temp = TempDir::new()?;
+        // .
+        // └── colors/
+        //    ├── pipe (FIFO)
+        //    └── red
+        temp.child("colors/red").write_str("blood")?;
+        let status = Command::new("mkfifo").arg(temp.child("colors/pipe").path()).status()?;
+        ensure!(status.success(), "mkfifo failed with {status}");
+        let result = launch_work(&temp, ["colors"], datetime!(2022-12-13 14:15:16 UTC));
+        check_err_contains(result, "is a FIFO, a socket or a device, so it cannot be copied")?;
+        // The partial copy, with `red` but without `pipe`, is removed.
+        temp.child("colors_2022-12-13-14h15").check_does_not_exist()
+    }
+
     #[test]
     fn fancy_directory_names() -> anyhow::Result<()> {
         let temp = TempDir::new()?;
@@ -361,8 +1088,17 @@ mod tests {
         temp: &TempDir,
         arg_paths: [&str; N],
         now: OffsetDateTime,
+    ) -> anyhow::Result<()> {
+        launch_work_with(temp, Options::default(), arg_paths, now)
+    }
+
+    fn launch_work_with<const N: usize>(
+        temp: &TempDir,
+        options: Options,
+        arg_paths: [&str; N],
+        now: OffsetDateTime,
     ) -> anyhow::Result<()> {
         let src_paths = arg_paths.iter().map(|path| temp.child(path).to_path_buf()).collect();
-        work(src_paths, now)
+        work(src_paths, options, now)
     }
 }
@@ -0,0 +1,128 @@
+//! Policy which tells which backups of a path to keep, like `last=3,daily=7,weekly=4,monthly=12`
+
+use std::str::FromStr;
+
+use anyhow::{Context as _, bail, ensure};
+use time::PrimitiveDateTime;
+
+use common::quote;
+
+/// Numbers of backups to keep: the most recent ones and the most recent one of each day, week and
+/// month. A backup is kept if any of these numbers keeps it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    last: usize,
+    daily: usize,
+    weekly: usize,
+    monthly: usize,
+}
+
+impl RetentionPolicy {
+    /// Tell which backups to keep, given their datetimes from the oldest to the most recent.
+    pub fn keep(&self, datetimes: &[PrimitiveDateTime]) -> Vec<bool> {
+        let mut kept = vec![false; datetimes.len()];
+        for is_kept in kept.iter_mut().rev().take(self.last) {
+            *is_kept = true;
+        }
+        keep_the_most_recent_of_each_period(datetimes, self.daily, &mut kept, |datetime| {
+            let date = datetime.date();
+            (date.year(), date.ordinal())
+        });
+        keep_the_most_recent_of_each_period(datetimes, self.weekly, &mut kept, |datetime| {
+            let (year, week, _) = datetime.to_iso_week_date();
+            (year, u16::from(week))
+        });
+        keep_the_most_recent_of_each_period(datetimes, self.monthly, &mut kept, |datetime| {
+            (datetime.year(), u16::from(u8::from(datetime.month())))
+        });
+        kept
+    }
+}
+
+/// Keep the most recent backup of each of the `count` most recent periods which have a backup.
+fn keep_the_most_recent_of_each_period(
+    datetimes: &[PrimitiveDateTime],
+    count: usize,
+    kept: &mut [bool],
+    period: impl Fn(PrimitiveDateTime) -> (i32, u16),
+) {
+    let mut remaining_count = count;
+    let mut previous_period = None;
+    for (&datetime, is_kept) in datetimes.iter().zip(kept).rev() {
+        if remaining_count == 0 {
+            break;
+        }
+        let current_period = Some(period(datetime));
+        if current_period != previous_period {
+            *is_kept = true;
+            remaining_count -= 1;
+            previous_period = current_period;
+        }
+    }
+}
+
+impl FromStr for RetentionPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> anyhow::Result<Self> {
+        let mut policy = Self::default();
+        for rule in text.split(',') {
+            let (period, count) = rule
+                .split_once('=')
+                .with_context(|| format!("{} does not look like `daily=7`", quote(rule)))?;
+            let count =
+                count.parse().with_context(|| format!("invalid number in {}", quote(rule)))?;
+            let field = match period {
+                "last" => &mut policy.last,
+                "daily" => &mut policy.daily,
+                "weekly" => &mut policy.weekly,
+                "monthly" => &mut policy.monthly,
+                _ => bail!(
+                    "unknown period {}, instead of `last`, `daily`, `weekly` or `monthly`",
+                    quote(period)
+                ),
+            };
+            *field = count;
+        }
+        ensure!(policy != Self::default(), "the policy would not keep any backup");
+        Ok(policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use time::macros::datetime;
+
+    use common::check_err_contains;
+
+    #[test]
+    fn parse_a_policy() -> anyhow::Result<()> {
+        let policy: RetentionPolicy = "last=3,weekly=4,monthly=12".parse()?;
+        ensure!(policy == RetentionPolicy { last: 3, daily: 0, weekly: 4, monthly: 12 });
+        check_err_contains("yearly=2".parse::<RetentionPolicy>(), "unknown period")?;
+        check_err_contains("daily".parse::<RetentionPolicy>(), "does not look like")?;
+        check_err_contains("daily=-1".parse::<RetentionPolicy>(), "invalid number")?;
+        check_err_contains("last=0".parse::<RetentionPolicy>(), "would not keep any backup")
+    }
+
+    #[test]
+    fn keep_the_last_backups_and_the_most_recent_of_each_period() -> anyhow::Result<()> {
+        let datetimes = [
+            datetime!(2022-10-31 09:00), // monthly
+            datetime!(2022-11-01 09:00),
+            datetime!(2022-11-30 09:00), // monthly
+            datetime!(2022-12-04 09:00), // daily and weekly (Sunday of the previous week)
+            datetime!(2022-12-05 09:00),
+            datetime!(2022-12-05 18:00), // daily
+            datetime!(2022-12-06 09:00),
+            datetime!(2022-12-06 12:00), // last
+            datetime!(2022-12-06 18:00), // last, daily, weekly and monthly
+        ];
+        let policy: RetentionPolicy = "last=2,daily=3,weekly=2,monthly=3".parse()?;
+        let kept = policy.keep(&datetimes);
+        ensure!(kept == [true, false, true, true, false, true, false, true, true], "{kept:?}");
+        Ok(())
+    }
+}
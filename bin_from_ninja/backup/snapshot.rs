@@ -0,0 +1,64 @@
+//! Backups of a path, whose names end with the datetime of the backup
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context as _;
+use time::format_description::BorrowedFormatItem;
+use time::macros::format_description;
+use time::{OffsetDateTime, PrimitiveDateTime};
+
+use common::{quote, quote_path};
+
+/// Format of the datetime at the end of the name of a backup, like `2022-12-13-14h15`
+const DATETIME_FORMAT: &[BorrowedFormatItem<'_>] =
+    format_description!("[year]-[month]-[day]-[hour]h[minute]");
+
+pub struct Snapshot {
+    pub path: PathBuf,
+    pub datetime: PrimitiveDateTime,
+}
+
+/// Get the suffix of the name of a backup made at `datetime`, like `_2022-12-13-14h15`
+pub fn suffix(datetime: OffsetDateTime) -> String {
+    format!("_{}", format_datetime(PrimitiveDateTime::new(datetime.date(), datetime.time())))
+}
+
+pub fn format_datetime(datetime: PrimitiveDateTime) -> String {
+    datetime.format(DATETIME_FORMAT).unwrap()
+}
+
+/// Parse a datetime written like at the end of the name of a backup, like `2022-12-13-14h15`
+pub fn parse_datetime(text: &str) -> anyhow::Result<PrimitiveDateTime> {
+    PrimitiveDateTime::parse(text, DATETIME_FORMAT)
+        .with_context(|| format!("{} does not look like `2022-12-13-14h15`", quote(text)))
+}
+
+/// Find the backups of `path` in its directory, from the oldest to the most recent.
+pub fn find(path: &Path) -> anyhow::Result<Vec<Snapshot>> {
+    let file_name =
+        path.file_name().with_context(|| format!("{} does not have a name", quote_path(path)))?;
+    let dir_path = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let entries = fs::read_dir(dir_path)
+        .with_context(|| format!("failed to read the directory {}", quote_path(dir_path)))?;
+    let mut snapshots = Vec::new();
+    for entry in entries {
+        let entry = entry
+            .with_context(|| format!("failed to read an entry of {}", quote_path(dir_path)))?;
+        let entry_name = entry.file_name();
+        let datetime = entry_name
+            .as_encoded_bytes()
+            .strip_prefix(file_name.as_encoded_bytes())
+            .and_then(|rest| rest.strip_prefix(b"_"))
+            .and_then(|rest| str::from_utf8(rest).ok())
+            .and_then(|text| parse_datetime(text).ok());
+        if let Some(datetime) = datetime {
+            snapshots.push(Snapshot { path: path.with_file_name(entry_name), datetime });
+        }
+    }
+    snapshots.sort_by_key(|snapshot| snapshot.datetime);
+    Ok(snapshots)
+}
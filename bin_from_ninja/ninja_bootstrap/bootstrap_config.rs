@@ -0,0 +1,224 @@
+//! Configuration of `ninja_bootstrap`
+//!
+//! Each setting can be given on the command line or in an optional `bootstrap.toml` file in the
+//! current directory, next to `build.ninja`, for example:
+//!
+//! ```toml
+//! install_prefix = "/usr/local"
+//! profile = "dev"
+//! clippy_lints = ["clippy::unwrap_used"]
+//! target = "x86_64-unknown-linux-musl"
+//! offline = false
+//! coverage = true
+//! ```
+//!
+//! The command line wins, except for the clippy lints, which are added to the ones of the file.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context as _;
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::Args;
+use serde::Deserialize;
+
+use common::quote_path;
+
+#[derive(Args, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// Directory whose `bin` subdirectory receives the deployed binaries [default: $HOME]
+    #[arg(long)]
+    install_prefix: Option<PathBuf>,
+    /// Cargo profile of the deployed binaries [default: release]
+    #[arg(long)]
+    profile: Option<String>,
+    /// Extra lint checked by clippy, like `clippy::unwrap_used` (repeatable)
+    #[arg(long = "clippy-lint", value_name = "LINT")]
+    #[serde(default)]
+    clippy_lints: Vec<String>,
+    /// Target triple of the deployed binaries [default: the host]
+    #[arg(long)]
+    target: Option<String>,
+    /// Whether cargo must not access the network [default: true]
+    #[arg(long)]
+    offline: Option<bool>,
+    /// Whether to write the `coverage` target, which needs `cargo llvm-cov` [default: false]
+    #[arg(long)]
+    coverage: Option<bool>,
+}
+
+/// Settings with the default values filled in
+pub struct BootstrapConfig {
+    pub install_prefix: PathBuf,
+    pub profile: String,
+    pub clippy_lints: Vec<String>,
+    pub target: Option<String>,
+    pub offline: bool,
+    pub coverage: bool,
+}
+
+impl Settings {
+    /// Read the settings of `path`, or return the default settings if the file does not exist.
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(content) => toml::from_str(&content)
+                .with_context(|| format!("failed to parse {}", quote_path(path))),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(error) => {
+                Err(error).with_context(|| format!("failed to read {}", quote_path(path)))
+            }
+        }
+    }
+
+    /// Combine with the settings of a file, which have a lower priority.
+    pub fn or(self, file_settings: Self) -> Self {
+        Self {
+            install_prefix: self.install_prefix.or(file_settings.install_prefix),
+            profile: self.profile.or(file_settings.profile),
+            clippy_lints: file_settings.clippy_lints.into_iter().chain(self.clippy_lints).collect(),
+            target: self.target.or(file_settings.target),
+            offline: self.offline.or(file_settings.offline),
+            coverage: self.coverage.or(file_settings.coverage),
+        }
+    }
+
+    pub fn into_config(self, home_path: &Path) -> BootstrapConfig {
+        BootstrapConfig {
+            install_prefix: self.install_prefix.unwrap_or_else(|| home_path.to_owned()),
+            profile: self.profile.unwrap_or_else(|| "release".into()),
+            clippy_lints: self.clippy_lints,
+            target: self.target,
+            offline: self.offline.unwrap_or(true),
+            coverage: self.coverage.unwrap_or(false),
+        }
+    }
+}
+
+impl BootstrapConfig {
+    /// Flags given to every cargo command
+    pub const fn cargo_flags(&self) -> &'static str {
+        if self.offline { "--offline --frozen" } else { "--locked" }
+    }
+
+    /// Flags given to `cargo build` to compile the deployed binaries
+    pub fn build_flags(&self) -> String {
+        let mut flags = format!("--profile {}", self.profile);
+        if let Some(target) = &self.target {
+            flags.push_str(" --target ");
+            flags.push_str(target);
+        }
+        flags
+    }
+
+    /// Flags given to `rustc` by `cargo clippy`
+    pub fn clippy_flags(&self) -> String {
+        let mut flags = String::from("-D warnings");
+        for lint in &self.clippy_lints {
+            flags.push_str(" -W ");
+            flags.push_str(lint);
+        }
+        flags
+    }
+
+    /// Directory of the deployed binaries
+    pub fn install_path(&self) -> PathBuf {
+        self.install_prefix.join("bin")
+    }
+
+    /// Directory where cargo writes the compiled binaries
+    pub fn output_directory(&self, cargo_target_dir: &Utf8Path) -> Utf8PathBuf {
+        let mut path = cargo_target_dir.to_owned();
+        if let Some(target) = &self.target {
+            path.push(target);
+        }
+        // https://doc.rust-lang.org/cargo/reference/profiles.html#custom-profiles
+        path.push(match self.profile.as_str() {
+            "dev" | "test" => "debug",
+            "bench" => "release",
+            profile => profile,
+        });
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_command_line_wins_over_the_file() -> anyhow::Result<()> {
+        let file_settings: Settings = toml::from_str(
+            r#"
+            install_prefix = "/usr/local"
+            profile = "dev"
+            clippy_lints = ["clippy::unwrap_used"]
+            target = "x86_64-unknown-linux-musl"
+            offline = false
+            coverage = true
+            "#,
+        )?;
+        let settings = Settings {
+            profile: Some("bench".into()),
+            clippy_lints: vec!["clippy::expect_used".into()],
+            offline: Some(true),
+            ..Settings::default()
+        };
+        let config = settings.or(file_settings).into_config(Path::new("/home/user"));
+        assert_eq!(config.install_prefix, Path::new("/usr/local"));
+        assert_eq!(config.profile, "bench");
+        assert_eq!(config.clippy_lints, ["clippy::unwrap_used", "clippy::expect_used"]);
+        assert_eq!(config.target.as_deref(), Some("x86_64-unknown-linux-musl"));
+        assert!(config.offline);
+        assert!(config.coverage);
+        assert_eq!(
+            config.clippy_flags(),
+            "-D warnings -W clippy::unwrap_used -W clippy::expect_used"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn fill_in_the_default_values() -> anyhow::Result<()> {
+        let settings = Settings::read(Path::new("does/not/exist/bootstrap.toml"))?;
+        let config = settings.or(Settings::default()).into_config(Path::new("/home/user"));
+        assert_eq!(config.install_path(), Path::new("/home/user/bin"));
+        assert_eq!(config.profile, "release");
+        assert!(config.clippy_lints.is_empty());
+        assert_eq!(config.target, None);
+        assert!(config.offline);
+        assert!(!config.coverage);
+        assert_eq!(config.cargo_flags(), "--offline --frozen");
+        assert_eq!(config.build_flags(), "--profile release");
+        Ok(())
+    }
+
+    #[test]
+    fn reject_an_unknown_field() {
+        let error = toml::from_str::<Settings>("prefix = \"/usr/local\"").err().unwrap();
+        assert!(error.to_string().contains("unknown field `prefix`"), "{error}");
+    }
+
+    #[test]
+    fn get_the_output_directory() {
+        let get_output_directory = |profile: &str, target: Option<&str>| {
+            let config = Settings {
+                profile: Some(profile.into()),
+                target: target.map(Into::into),
+                ..Settings::default()
+            }
+            .into_config(Path::new("/home/user"));
+            config.output_directory(Utf8Path::new("/ws/target")).into_string()
+        };
+        assert_eq!(get_output_directory("dev", None), "/ws/target/debug");
+        assert_eq!(get_output_directory("test", None), "/ws/target/debug");
+        assert_eq!(get_output_directory("release", None), "/ws/target/release");
+        assert_eq!(get_output_directory("bench", None), "/ws/target/release");
+        assert_eq!(get_output_directory("fast", None), "/ws/target/fast");
+        assert_eq!(
+            get_output_directory("release", Some("x86_64-unknown-linux-musl")),
+            "/ws/target/x86_64-unknown-linux-musl/release"
+        );
+    }
+}
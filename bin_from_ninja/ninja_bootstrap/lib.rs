@@ -0,0 +1,7 @@
+//! Library part of `ninja_bootstrap`
+
+pub mod ninja_async_writer;
+pub mod ninja_diff;
+pub mod ninja_graph;
+pub mod ninja_parser;
+pub mod ninja_writer;
@@ -4,196 +4,1521 @@
 //! `build.ninja`.
 //!
 //! `build.ninja` is in `.gitignore`, but you can look at `example.ninja`, which is almost a copy
-//! of `build.ninja`.
-
-mod ninja_writer;
+//! of `build.ninja`. `make diff-example` runs `ninja_bootstrap diff` to compare them.
+//!
+//! With `--format json` or `--format dot`, the graph of the build edges is written instead, to
+//! see why an edge is run again.
+//!
+//! With `--workspace <dir>...`, the written file includes with `subninja` a `workspace.ninja` file
+//! written in each workspace directory. Its stamp files and phony targets are prefixed with the
+//! workspace directory, like `coroutine/check`, and the phony targets of the main file, like
+//! `check`, depend on the ones of each workspace.
 
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
 use std::fs;
 use std::io::{self, Write};
 use std::iter;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
 use anyhow::Context as _;
-use camino::Utf8PathBuf;
-use cargo_metadata::MetadataCommand;
-use glob::glob;
-use serde::Deserialize;
-use toml::Value;
-use toml::value::Table;
+use camino::{Utf8Path, Utf8PathBuf};
+use cargo_metadata::{DependencyKind, Metadata, MetadataCommand, Package, PackageId};
+use clap::{Parser, Subcommand, ValueEnum};
+
+mod bootstrap_config;
+
+use bootstrap_config::{BootstrapConfig, Settings};
+use common::quote_path;
+use ninja_bootstrap::ninja_diff::{self, Change};
+use ninja_bootstrap::ninja_graph::{Edge, Graph};
+use ninja_bootstrap::ninja_parser::{self, NinjaFile};
+use ninja_bootstrap::ninja_writer::{Config, Deps, Expr, NinjaWriter, Validation};
+
+#[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
+/// Write a Ninja build file to stdout.
+///
+/// The settings can also be written in a `bootstrap.toml` file in the current directory.
+struct Cli {
+    #[command(flatten)]
+    settings: Settings,
+    /// Format of the output
+    #[arg(long, value_enum, default_value_t = Format::Ninja)]
+    format: Format,
+    /// Directory of a workspace to build, relative to the current directory (repeatable)
+    /// [default: the workspace of the current directory, without `subninja`]
+    #[arg(long = "workspace", value_name = "DIR")]
+    workspaces: Vec<Utf8PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    /// Ninja build file
+    Ninja,
+    /// Graph of the build edges in JSON
+    Json,
+    /// Graph of the build edges in the DOT language of Graphviz
+    Dot,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Compare two Ninja files and exit with 1 if they differ or with 2 if the comparison fails,
+    /// like `diff`.
+    ///
+    /// The home directory and the cargo target directory of each file are replaced with `$HOME`
+    /// and `$CARGO_TARGET_DIR` before the comparison. By default, they are the ones of the
+    /// current machine.
+    Diff {
+        old_path: PathBuf,
+        new_path: PathBuf,
+        /// Home directory in the old file
+        #[arg(long)]
+        old_home: Option<PathBuf>,
+        /// Cargo target directory in the old file
+        #[arg(long)]
+        old_target_dir: Option<PathBuf>,
+        /// Home directory in the new file
+        #[arg(long)]
+        new_home: Option<PathBuf>,
+        /// Cargo target directory in the new file
+        #[arg(long)]
+        new_target_dir: Option<PathBuf>,
+    },
+}
+
+/// Exit with 2 on error, so that `diff` can exit with 1 when the files differ.
+fn main() -> ExitCode {
+    match run() {
+        Ok(exit_code) => exit_code,
+        Err(error) => {
+            // Like the `Termination` implementation of `Result`, write the causes and the
+            // backtrace. If even this fails, there is nothing left to report it.
+            #[expect(clippy::use_debug, reason = "the causes and the backtrace are wanted")]
+            drop(writeln!(io::stderr(), "Error: {error:?}"));
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn run() -> anyhow::Result<ExitCode> {
+    let Cli { settings, format, workspaces, command } = Cli::parse();
+    match command {
+        None => {
+            let settings = settings.or(Settings::read(Path::new("bootstrap.toml"))?);
+            let home_path = env::home_dir().context("failed to get the home directory path")?;
+            let config = settings.into_config(&home_path);
+            let has_subninjas = !workspaces.is_empty();
+            let workspaces = if has_subninjas {
+                workspaces
+                    .into_iter()
+                    .map(|directory| Workspace::load(directory, config.offline))
+                    .collect::<anyhow::Result<_>>()?
+            } else {
+                vec![Workspace::load(Utf8PathBuf::new(), config.offline)?]
+            };
+            let NinjaFiles { main, subninjas, edges } =
+                write_ninja_files(&workspaces, has_subninjas, &config)?;
+            match format {
+                Format::Ninja => {
+                    for (path, subninja_content) in &subninjas {
+                        fs::write(path, subninja_content)
+                            .with_context(|| format!("failed to write {}", quote_path(path)))?;
+                    }
+                    io::stdout().lock().write_all(&main).context("failed to write to stdout")?;
+                }
+                Format::Json | Format::Dot => {
+                    let graph = Graph::new(edges);
+                    let out = io::stdout().lock();
+                    match format {
+                        Format::Json => graph.write_json(out),
+                        _ => graph.write_dot(out),
+                    }
+                    .context("failed to write to stdout")?;
+                }
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Command::Diff {
+            old_path,
+            new_path,
+            old_home,
+            old_target_dir,
+            new_home,
+            new_target_dir,
+        }) => {
+            let home_path = env::home_dir().context("failed to get the home directory path")?;
+            let cargo_target_dir =
+                get_cargo_target_dir().context("failed to get cargo target directory")?;
+            let cargo_target_dir = cargo_target_dir.as_std_path();
+            let old = read_normalized_ninja_file(
+                &old_path,
+                old_home.as_deref().unwrap_or(&home_path),
+                old_target_dir.as_deref().unwrap_or(cargo_target_dir),
+            )?;
+            let new = read_normalized_ninja_file(
+                &new_path,
+                new_home.as_deref().unwrap_or(&home_path),
+                new_target_dir.as_deref().unwrap_or(cargo_target_dir),
+            )?;
+            let changes = ninja_diff::diff(&old, &new);
+            let mut out = io::stdout().lock();
+            changes
+                .iter()
+                .try_for_each(|change: &Change| write!(out, "{change}"))
+                .context("failed to write to stdout")?;
+            Ok(if changes.is_empty() { ExitCode::SUCCESS } else { ExitCode::FAILURE })
+        }
+    }
+}
+
+/// Content of the written Ninja files
+struct NinjaFiles {
+    /// Content of `build.ninja`
+    main: Vec<u8>,
+    /// Content of the file of each workspace, which `build.ninja` includes with `subninja`
+    subninjas: BTreeMap<PathBuf, Vec<u8>>,
+    /// Build edges of all the files, as fed to the writers
+    edges: Vec<Edge>,
+}
+
+/// Write the main Ninja file and, if `has_subninjas`, the file of each workspace.
+fn write_ninja_files(
+    workspaces: &[Workspace],
+    has_subninjas: bool,
+    config: &BootstrapConfig,
+) -> anyhow::Result<NinjaFiles> {
+    let mut main = Vec::new();
+    let mut subninjas = BTreeMap::new();
+    let mut ninja_writer = NinjaWriter::new(Config::with_width(100), &mut main);
+    ninja_writer.enable_validation(Validation::new());
+    ninja_writer.record_edges(Vec::new());
+    ninja_writer.comment(HEADER)?;
+    write_section_header(&mut ninja_writer, "Variables and pools")?;
+    write_variables(&mut ninja_writer, config)?;
+    write_pools(&mut ninja_writer)?;
+    write_section_header(&mut ninja_writer, "Rules")?;
+    write_rules(&mut ninja_writer, config)?;
+    let subninja_paths: Vec<String> = if has_subninjas {
+        workspaces.iter().map(|workspace| workspace.path("workspace.ninja")).collect()
+    } else {
+        Vec::new()
+    };
+    write_section_header(&mut ninja_writer, "Bootstrap and installation directory")?;
+    write_bootstrap_build(&mut ninja_writer, workspaces, &subninja_paths)?;
+    ninja_writer.build()?.output(config.install_path())?.rule("create_directory")?.end()?;
+    if has_subninjas {
+        write_section_header(&mut ninja_writer, "Workspaces")?;
+        for (workspace, subninja_path) in workspaces.iter().zip(subninja_paths) {
+            ninja_writer.subninja(&subninja_path)?;
+            let mut subninja_content = Vec::new();
+            let mut subninja_writer =
+                NinjaWriter::new(Config::with_width(100), &mut subninja_content);
+            // The same graph covers the main file and all the included files.
+            subninja_writer.enable_validation(ninja_writer.take_validation().unwrap_or_default());
+            subninja_writer.record_edges(ninja_writer.take_recorded_edges().unwrap_or_default());
+            subninja_writer.comment(HEADER)?;
+            // The commands are run in the directory of the main file.
+            subninja_writer.variable("manifest_flags", workspace.manifest_flags())?;
+            write_builds(&mut subninja_writer, workspace, config)?;
+            ninja_writer.enable_validation(subninja_writer.take_validation().unwrap_or_default());
+            ninja_writer.record_edges(subninja_writer.take_recorded_edges().unwrap_or_default());
+            subninjas.insert(subninja_path.into(), subninja_content);
+        }
+        write_section_header(&mut ninja_writer, "Phony targets of all the workspaces")?;
+        let coverage_target = config.coverage.then_some("coverage");
+        for target in PHONY_TARGETS.into_iter().chain(coverage_target) {
+            ninja_writer
+                .build()?
+                .output(target)?
+                .rule("phony")?
+                .inputs(workspaces.iter().map(|workspace| workspace.path(target)))?
+                .end()?;
+        }
+    } else {
+        write_builds(&mut ninja_writer, &workspaces[0], config)?;
+    }
+    write_section_header(&mut ninja_writer, "Default target")?;
+    ninja_writer.default("check")?.end()?;
+    if let Some(validation) = ninja_writer.take_validation() {
+        validation.check_cycles()?;
+    }
+    let edges = ninja_writer.take_recorded_edges().unwrap_or_default();
+    Ok(NinjaFiles { main, subninjas, edges })
+}
+
+fn read_normalized_ninja_file(
+    path: &Path,
+    home_path: &Path,
+    cargo_target_dir: &Path,
+) -> anyhow::Result<NinjaFile> {
+    let content = fs::read(path).with_context(|| format!("failed to read {}", quote_path(path)))?;
+    let mut ninja_file = ninja_parser::parse(&content)
+        .with_context(|| format!("failed to parse {}", quote_path(path)))?;
+    ninja_diff::normalize(
+        &mut ninja_file,
+        &[
+            (home_path.as_os_str().as_encoded_bytes(), "HOME"),
+            (cargo_target_dir.as_os_str().as_encoded_bytes(), "CARGO_TARGET_DIR"),
+        ],
+    );
+    Ok(ninja_file)
+}
+
+/// Comment at the beginning of each written file
+const HEADER: &str = "Written by ninja_bootstrap, so do not edit this file: Ninja writes it again \
+    when a manifest, `Cargo.lock`, `bootstrap.toml` or a source file of `ninja_bootstrap` changes. \
+    The absolute paths depend on the home directory and on the cargo target directory.";
 
-use ninja_writer::{Config, NinjaWriter};
+/// Write a blank line and a comment which introduces the next definitions.
+fn write_section_header<W: Write>(
+    ninja_writer: &mut NinjaWriter<W>,
+    title: &str,
+) -> anyhow::Result<()> {
+    ninja_writer.blank_line()?;
+    ninja_writer.comment(title)?;
+    Ok(())
+}
 
-fn main() -> anyhow::Result<()> {
-    let mut out = io::stdout().lock();
-    let mut ninja_writer = NinjaWriter::new(Config::with_width(100), &mut out);
-    write_rules(&mut ninja_writer)?;
-    write_builds(&mut ninja_writer)
+/// Phony targets of each workspace, which the main file aggregates when there are several
+const PHONY_TARGETS: [&str; 7] =
+    ["fmt", "check", "check-all", "deploy", "uninstall", "clean", "clean-all"];
+
+fn write_variables<W: Write>(
+    ninja_writer: &mut NinjaWriter<W>,
+    config: &BootstrapConfig,
+) -> anyhow::Result<()> {
+    // The clippy and test edges concatenate several dependency files of cargo, so their `depfile`
+    // has several targets, which Ninja supports since its version 1.10.
+    ninja_writer.variable("ninja_required_version", "1.10")?;
+    ninja_writer.variable("cargo_flags", config.cargo_flags())?;
+    ninja_writer.variable("build_flags", config.build_flags())?;
+    ninja_writer.variable("clippy_flags", config.clippy_flags())?;
+    Ok(())
 }
 
-fn write_rules<W: Write>(ninja_writer: &mut NinjaWriter<W>) -> anyhow::Result<()> {
-    ninja_writer.rule("create_directory")?.command("mkdir -p -- $out")?.end()?;
-    ninja_writer.rule("fmt")?.command("cargo fmt -p $project && touch $out")?.end()?;
+fn write_pools<W: Write>(ninja_writer: &mut NinjaWriter<W>) -> anyhow::Result<()> {
+    // The cargo commands which compile code fight over the lock of the target directory and the
+    // CPU, so they are run one at a time.
+    ninja_writer.pool("cargo")?.depth(1)?.end()?;
+    Ok(())
+}
+
+fn write_rules<W: Write>(
+    ninja_writer: &mut NinjaWriter<W>,
+    config: &BootstrapConfig,
+) -> anyhow::Result<()> {
+    ninja_writer
+        .rule("create_directory")?
+        .command(Expr::new().text("mkdir -p -- ").variable("out"))?
+        .description(Expr::new().text("create directory ").variable("out"))?
+        .end()?;
+    ninja_writer
+        .rule("fmt")?
+        .command(
+            Expr::new()
+                .text("cargo fmt ")
+                .variable("manifest_flags")
+                .text(" -p ")
+                .variable("project")
+                .text(" && touch ")
+                .variable("out"),
+        )?
+        .description(Expr::new().text("fmt ").variable("project"))?
+        .end()?;
+    // `$manifest_flags` is only defined with `--workspace`, because the commands are then run
+    // outside of the workspaces.
+    //
+    // `cargo clippy` and `cargo test` only write dependency files named with a hash in `deps`,
+    // so `cargo build` runs first to write the ones of the library and the binaries in the
+    // profile directory. It is given the flags of the deployed binaries, which the `compile` edge
+    // then finds up-to-date, so the extra build mostly costs the packages which are not
+    // deployed. Ninja reads the concatenated files to know the source files of each project, then
+    // deletes the copy.
+    let build_command = || {
+        cargo(Expr::new(), "build")
+            .text(" ")
+            .variable("build_flags")
+            .text(" -p ")
+            .variable("project")
+            .text(" && ")
+    };
+    let command = build_command();
+    let command = cargo(command, "clippy")
+        .text(" --all-targets --all-features -p ")
+        .variable("project")
+        .text(" -- ")
+        .variable("clippy_flags");
     ninja_writer
         .rule("clippy")?
-        .command("cargo clippy --offline --frozen --all-targets --all-features -p $project -- -D warnings && touch $out")?
+        .command(concatenate_depfiles_and_touch(command))?
+        .description(Expr::new().text("clippy ").variable("project"))?
+        .depfile(Expr::new().variable("out").text(".d"))?
+        .deps(Deps::Gcc)?
+        .pool("cargo")?
         .end()?;
+    let command = cargo(build_command(), "test")
+        .text(" -p ")
+        .variable("project")
+        .text(" ")
+        .variable("test_flags");
     ninja_writer
         .rule("test")?
-        .command("cargo test --offline --frozen -p $project && touch $out")?
+        .command(concatenate_depfiles_and_touch(command))?
+        .description(
+            Expr::new().text("test ").variable("project").text(" ").variable("test_flags"),
+        )?
+        .depfile(Expr::new().variable("out").text(".d"))?
+        .deps(Deps::Gcc)?
+        .pool("cargo")?
         .end()?;
+    // `restat` because, when the binary is already up-to-date, `cargo build` does not touch it, so
+    // the `copy` edge does not need to be run again. `$out` may contain several binaries, so each
+    // build edge gives its own `depfile`.
     ninja_writer
-        .rule("release")?
-        .command("cargo build --offline --frozen --release -p $project && touch $out")?
+        .rule("compile")?
+        .command(
+            cargo(Expr::new(), "build")
+                .text(" ")
+                .variable("build_flags")
+                .text(" -p ")
+                .variable("project")
+                .text(" && cat -- ")
+                .variable("cargo_depfiles")
+                .text(" > ")
+                .variable("depfile"),
+        )?
+        .description(Expr::new().text("compile ").variable("project"))?
+        .deps(Deps::Gcc)?
+        .restat()?
+        .pool("cargo")?
+        .end()?;
+    write_extra_check_rules(ninja_writer, config)?;
+    ninja_writer
+        .rule("copy")?
+        .command(Expr::new().text("cp -- ").variable("in").text(" ").variable("out"))?
+        .description(Expr::new().text("copy ").variable("out"))?
+        .end()?;
+    write_removal_rules(ninja_writer)?;
+    // `generator` because `build.ninja` must not be removed by `ninja -t clean` and because a
+    // change of its command must not be a reason to write it again.
+    ninja_writer
+        .rule("bootstrap")?
+        .command(
+            cargo(Expr::new(), "run")
+                .text(" -q -p ninja_bootstrap -- ")
+                .variable("bootstrap_flags")
+                .text(" > ")
+                .variable("out")
+                .text(".tmp && mv -- ")
+                .variable("out")
+                .text(".tmp ")
+                .variable("out"),
+        )?
+        .description(Expr::new().text("bootstrap ").variable("out"))?
+        .generator()?
         .end()?;
-    ninja_writer.rule("copy")?.command("cp -- $in $out")?.end()?;
     Ok(())
 }
 
-fn write_builds<W: Write>(ninja_writer: &mut NinjaWriter<W>) -> anyhow::Result<()> {
-    let cargo_target_dir =
-        get_cargo_target_dir().context("failed to get cargo target directory")?;
-    let cargo_toml = fs::read_to_string("Cargo.toml").context("failed to read Cargo.toml")?;
-    let cargo_toml =
-        toml::from_str::<CargoToml>(&cargo_toml).context("failed to parse Cargo.toml")?;
-    let projects = cargo_toml.workspace.members;
-    let home_path = env::home_dir().context("failed to get the home directory path")?;
-    let bin_path = home_path.join("bin");
-    ninja_writer.build()?.unix_output(&bin_path)?.rule("create_directory")?.end()?;
-    for project in &projects {
+/// Write the rules of the `check-all` and `coverage` targets.
+fn write_extra_check_rules<W: Write>(
+    ninja_writer: &mut NinjaWriter<W>,
+    config: &BootstrapConfig,
+) -> anyhow::Result<()> {
+    ninja_writer
+        .rule("doc")?
+        .command(
+            cargo(Expr::new().text("RUSTDOCFLAGS='-D warnings' "), "doc")
+                .text(" --no-deps -p ")
+                .variable("project")
+                .text(" && touch ")
+                .variable("out"),
+        )?
+        .description(Expr::new().text("doc ").variable("project"))?
+        .pool("cargo")?
+        .end()?;
+    // The toolchain of the minimum supported Rust version is installed by `rustup`. Its artifacts are
+    // written in their own target directory, so that they do not overwrite the binaries and the
+    // dependency files of the default toolchain, which the other edges read.
+    ninja_writer
+        .rule("msrv")?
+        .command(
+            Expr::new()
+                .text("cargo +")
+                .variable("rust_version")
+                .text(" build ")
+                .variable("cargo_flags")
+                .text(" ")
+                .variable("manifest_flags")
+                .text(" --target-dir ")
+                .variable("msrv_target_dir")
+                .text(" --all-targets -p ")
+                .variable("project")
+                .text(" && touch ")
+                .variable("out"),
+        )?
+        .description(
+            Expr::new()
+                .text("msrv ")
+                .variable("project")
+                .text(" with Rust ")
+                .variable("rust_version"),
+        )?
+        .pool("cargo")?
+        .end()?;
+    if config.coverage {
+        ninja_writer
+            .rule("coverage")?
+            .command(
+                cargo(Expr::new(), "llvm-cov")
+                    .text(" -p ")
+                    .variable("project")
+                    .text(" --lcov --output-path ")
+                    .variable("out"),
+            )?
+            .description(Expr::new().text("coverage ").variable("project"))?
+            .pool("cargo")?
+            .end()?;
+        // The concatenation of LCOV files is a valid LCOV file.
+        ninja_writer
+            .rule("merge_coverage")?
+            .command(Expr::new().text("cat -- ").variable("in").text(" > ").variable("out"))?
+            .description(Expr::new().text("merge coverage ").variable("out"))?
+            .end()?;
+    }
+    Ok(())
+}
+
+/// Write the rules of the `uninstall`, `clean` and `clean-all` targets.
+fn write_removal_rules<W: Write>(ninja_writer: &mut NinjaWriter<W>) -> anyhow::Result<()> {
+    ninja_writer
+        .rule("uninstall")?
+        .command(
+            Expr::new()
+                .text("if cmp -s -- ")
+                .variable("built")
+                .text(" ")
+                .variable("deployed")
+                .text("; then rm -f -- ")
+                .variable("deployed")
+                .text("; elif test -e ")
+                .variable("deployed")
+                .text("; then echo \"keep ")
+                .variable("deployed")
+                .text(", which is not ")
+                .variable("built")
+                .text("\"; fi"),
+        )?
+        .description(Expr::new().text("uninstall ").variable("deployed"))?
+        .end()?;
+    ninja_writer
+        .rule("clean")?
+        .command(Expr::new().text("rm -f -- ").variable("paths"))?
+        .description(Expr::new().text("clean ").variable("project"))?
+        .end()?;
+    ninja_writer
+        .rule("cargo_clean")?
+        .command(cargo(Expr::new(), "clean").text(" -p ").variable("project"))?
+        .description(Expr::new().text("cargo clean ").variable("project"))?
+        .pool("cargo")?
+        .end()?;
+    Ok(())
+}
+
+/// Append `cargo <subcommand> $cargo_flags $manifest_flags` to the command.
+fn cargo(command: Expr, subcommand: &str) -> Expr {
+    command
+        .text(format!("cargo {subcommand} "))
+        .variable("cargo_flags")
+        .text(" ")
+        .variable("manifest_flags")
+}
+
+/// Append to the command the concatenation of the dependency files of cargo in `$out.d` and the
+/// update of the stamp file.
+fn concatenate_depfiles_and_touch(command: Expr) -> Expr {
+    command
+        .text(" && cat -- ")
+        .variable("cargo_depfiles")
+        .text(" > ")
+        .variable("out")
+        .text(".d && touch ")
+        .variable("out")
+}
+
+/// Write the build edges of the projects of the workspace and its phony targets.
+fn write_builds<W: Write>(
+    ninja_writer: &mut NinjaWriter<W>,
+    workspace: &Workspace,
+    config: &BootstrapConfig,
+) -> anyhow::Result<()> {
+    let projects = &workspace.projects;
+    let bin_path = config.install_path();
+    let output_directory = config.output_directory(&workspace.target_directory);
+    let cargo_lock = workspace.path("Cargo.lock");
+    let rustfmt_config =
+        Some(workspace.path("rustfmt.toml")).filter(|path| Path::new(path).is_file());
+    let mut deployed_paths = Vec::new();
+    let mut extra_check_paths = Vec::new();
+    for project in projects {
+        let directory = &project.directory;
+        write_section_header(ninja_writer, &format!("Package {} in {directory}", project.name))?;
         ninja_writer
             .build()?
-            .output(format!("{project}/fmt.ninjatarget"))?
+            .output(format!("{directory}/fmt.ninjatarget"))?
             .rule("fmt")?
-            .input("rustfmt.toml")?
-            .unix_input_results(glob(&format!("{project}/**/*.rs")).unwrap())?
-            .variable_and_value("project", project)?
+            .inputs(&rustfmt_config)?
+            .inputs(find_rust_sources(Path::new(directory))?)?
+            .variable_and_value("project", &project.name)?
             .end()?;
-        let local_dependencies = get_local_dependencies(project, &projects)?;
-        let clippy_and_test_inputs: Vec<String> = iter::once(project)
-            .chain(local_dependencies.normal_dependencies.iter())
-            .chain(local_dependencies.dev_dependencies.iter())
-            .map(|project| format!("{project}/fmt.ninjatarget"))
+        let clippy_and_test_inputs: Vec<String> = iter::once(directory)
+            .chain(project.normal_dependencies.iter())
+            .chain(project.dev_dependencies.iter())
+            .map(|directory| format!("{directory}/fmt.ninjatarget"))
             .collect();
+        // The `cargo build` of the clippy and test edges writes these dependency files, which list
+        // the source files of the project and of its local dependencies.
+        let cargo_depfiles =
+            join_for_shell(project.depfile_names.iter().map(|name| output_directory.join(name)));
         ninja_writer
             .build()?
-            .output(format!("{project}/clippy.ninjatarget"))?
+            .output(format!("{directory}/clippy.ninjatarget"))?
             .rule("clippy")?
-            .input("Cargo.lock")?
+            .input(&cargo_lock)?
             .inputs(clippy_and_test_inputs.iter())?
-            .variable_and_value("project", project)?
+            .variable_and_value("project", &project.name)?
+            .variable_and_value("cargo_depfiles", &cargo_depfiles)?
             .end()?;
+        // One stamp file per test target, so that a failed test target does not make Ninja run the
+        // other ones again.
+        for test_target in &project.test_targets {
+            ninja_writer
+                .build()?
+                .output(format!("{directory}/{}", test_target.stamp_name))?
+                .rule("test")?
+                .input(&cargo_lock)?
+                .inputs(clippy_and_test_inputs.iter())?
+                .variable_and_value("project", &project.name)?
+                .variable_and_value("test_flags", &test_target.flags)?
+                .variable_and_value("cargo_depfiles", &cargo_depfiles)?
+                .end()?;
+        }
+        extra_check_paths.extend(write_doc_and_msrv_builds(
+            ninja_writer,
+            project,
+            &cargo_lock,
+            &workspace.target_directory,
+        )?);
+        deployed_paths.extend(write_deploy_builds(
+            ninja_writer,
+            workspace,
+            project,
+            &output_directory,
+            &bin_path,
+        )?);
+    }
+    write_phony_builds(ninja_writer, workspace, extra_check_paths, deployed_paths)?;
+    if config.coverage {
+        write_coverage_builds(ninja_writer, workspace)?;
+    }
+    write_uninstall_builds(ninja_writer, workspace, &output_directory, &bin_path)?;
+    write_clean_builds(ninja_writer, workspace, config)
+}
+
+/// Write the phony targets of the workspace, except `coverage`, `uninstall`, `clean` and
+/// `clean-all`.
+fn write_phony_builds<W: Write>(
+    ninja_writer: &mut NinjaWriter<W>,
+    workspace: &Workspace,
+    extra_check_paths: Vec<String>,
+    deployed_paths: Vec<PathBuf>,
+) -> anyhow::Result<()> {
+    let projects = &workspace.projects;
+    write_section_header(ninja_writer, "Phony targets")?;
+    ninja_writer
+        .build()?
+        .output(workspace.path("fmt"))?
+        .rule("phony")?
+        .inputs(projects.iter().map(|project| format!("{}/fmt.ninjatarget", project.directory)))?
+        .end()?;
+    ninja_writer
+        .build()?
+        .output(workspace.path("check"))?
+        .rule("phony")?
+        .inputs(projects.iter().flat_map(Project::check_stamps))?
+        .end()?;
+    ninja_writer
+        .build()?
+        .output(workspace.path("check-all"))?
+        .rule("phony")?
+        .input(workspace.path("check"))?
+        .inputs(extra_check_paths)?
+        .end()?;
+    ninja_writer
+        .build()?
+        .output(workspace.path("deploy"))?
+        .rule("phony")?
+        .inputs(deployed_paths)?
+        .end()?;
+    Ok(())
+}
+
+/// Write the build edges which write a LCOV file per project and merge them in `coverage.info`,
+/// and the `coverage` phony target.
+fn write_coverage_builds<W: Write>(
+    ninja_writer: &mut NinjaWriter<W>,
+    workspace: &Workspace,
+) -> anyhow::Result<()> {
+    write_section_header(ninja_writer, "Coverage")?;
+    let cargo_lock = workspace.path("Cargo.lock");
+    for project in &workspace.projects {
         ninja_writer
             .build()?
-            .output(format!("{project}/test.ninjatarget"))?
-            .rule("test")?
-            .input("Cargo.lock")?
-            .inputs(clippy_and_test_inputs.iter())?
-            .variable_and_value("project", project)?
+            .output(format!("{}/lcov.info", project.directory))?
+            .rule("coverage")?
+            .input(&cargo_lock)?
+            .inputs(
+                iter::once(&project.directory)
+                    .chain(project.normal_dependencies.iter())
+                    .chain(project.dev_dependencies.iter())
+                    .map(|directory| format!("{directory}/fmt.ninjatarget")),
+            )?
+            .variable_and_value("project", &project.name)?
             .end()?;
-        if has_a_binary_to_deploy(project) {
-            let release_path = cargo_target_dir.join(format!("release/{project}"));
-            let project_and_normal_dependencies: Vec<String> =
-                iter::once(project.into()).chain(local_dependencies.normal_dependencies).collect();
+    }
+    let merged_path = workspace.path("coverage.info");
+    ninja_writer
+        .build()?
+        .output(&merged_path)?
+        .rule("merge_coverage")?
+        .inputs(
+            workspace.projects.iter().map(|project| format!("{}/lcov.info", project.directory)),
+        )?
+        .end()?;
+    ninja_writer
+        .build()?
+        .output(workspace.path("coverage"))?
+        .rule("phony")?
+        .input(merged_path)?
+        .end()?;
+    Ok(())
+}
+
+/// Write the `uninstall` phony target, which removes the deployed binaries which are identical to
+/// the compiled ones.
+///
+/// The outputs of the `uninstall` edges are never created, so these edges always run.
+fn write_uninstall_builds<W: Write>(
+    ninja_writer: &mut NinjaWriter<W>,
+    workspace: &Workspace,
+    output_directory: &Utf8Path,
+    bin_path: &Path,
+) -> anyhow::Result<()> {
+    write_section_header(ninja_writer, "Uninstallation")?;
+    let mut outputs = Vec::new();
+    for project in &workspace.projects {
+        for binary in &project.binaries_to_deploy {
+            let output = format!("{}/uninstall_{binary}.ninjatarget", project.directory);
+            let deployed_path = bin_path.join(binary);
             ninja_writer
                 .build()?
-                .output(release_path.as_str())?
-                .rule("release")?
-                .input("Cargo.lock")?
-                .inputs(
-                    project_and_normal_dependencies
-                        .iter()
-                        .map(|project| format!("{project}/fmt.ninjatarget")),
+                .output(&output)?
+                .rule("uninstall")?
+                .variable_and_value(
+                    "built",
+                    quote_for_shell(output_directory.join(binary).as_str().as_bytes()),
+                )?
+                .variable_and_value(
+                    "deployed",
+                    quote_for_shell(deployed_path.as_os_str().as_encoded_bytes()),
                 )?
-                .variable_and_value("project", project)?
-                .end()?;
-            ninja_writer
-                .build()?
-                .unix_output(bin_path.join(project))?
-                .rule("copy")?
-                .input(release_path.as_str())?
-                .implicit_dependencies(project_and_normal_dependencies.iter().flat_map(
-                    |project| {
-                        [
-                            format!("{project}/clippy.ninjatarget"),
-                            format!("{project}/test.ninjatarget"),
-                        ]
-                    },
-                ))?
-                .unix_order_only_dependency(&bin_path)?
                 .end()?;
+            outputs.push(output);
         }
     }
     ninja_writer
         .build()?
-        .output("fmt")?
+        .output(workspace.path("uninstall"))?
         .rule("phony")?
-        .inputs(projects.iter().map(|project| format!("{project}/fmt.ninjatarget")))?
+        .inputs(outputs)?
+        .end()?;
+    Ok(())
+}
+
+/// Write the `clean` phony target, which removes the stamp files and the coverage files, and the
+/// `clean-all` phony target, which also runs `cargo clean` on each project.
+///
+/// The outputs of the edges are never created, so these edges always run.
+fn write_clean_builds<W: Write>(
+    ninja_writer: &mut NinjaWriter<W>,
+    workspace: &Workspace,
+    config: &BootstrapConfig,
+) -> anyhow::Result<()> {
+    write_section_header(ninja_writer, "Cleaning")?;
+    let projects = &workspace.projects;
+    for project in projects {
+        let directory = &project.directory;
+        let mut stamps: Vec<String> = ["fmt", "clippy", "doc", "msrv"]
+            .iter()
+            .map(|stamp| format!("{directory}/{stamp}.ninjatarget"))
+            .chain(project.test_stamps())
+            .collect();
+        if config.coverage {
+            stamps.push(format!("{directory}/lcov.info"));
+        }
+        ninja_writer
+            .build()?
+            .output(format!("{directory}/clean.ninjatarget"))?
+            .rule("clean")?
+            .variable_and_value("project", &project.name)?
+            .variable_and_value("paths", join_for_shell(stamps))?
+            .end()?;
+        ninja_writer
+            .build()?
+            .output(format!("{directory}/cargo_clean.ninjatarget"))?
+            .rule("cargo_clean")?
+            .variable_and_value("project", &project.name)?
+            .end()?;
+    }
+    let mut clean_paths: Vec<String> =
+        projects.iter().map(|project| format!("{}/clean.ninjatarget", project.directory)).collect();
+    if config.coverage {
+        let clean_coverage_path = workspace.path("clean_coverage.ninjatarget");
+        ninja_writer
+            .build()?
+            .output(&clean_coverage_path)?
+            .rule("clean")?
+            .variable_and_value("project", "coverage")?
+            .variable_and_value("paths", join_for_shell([workspace.path("coverage.info")]))?
+            .end()?;
+        clean_paths.push(clean_coverage_path);
+    }
+    ninja_writer
+        .build()?
+        .output(workspace.path("clean"))?
+        .rule("phony")?
+        .inputs(clean_paths)?
         .end()?;
     ninja_writer
         .build()?
-        .output("check")?
+        .output(workspace.path("clean-all"))?
         .rule("phony")?
-        .inputs(projects.iter().flat_map(|project| {
-            [format!("{project}/clippy.ninjatarget"), format!("{project}/test.ninjatarget")]
-        }))?
+        .input(workspace.path("clean"))?
+        .inputs(
+            projects.iter().map(|project| format!("{}/cargo_clean.ninjatarget", project.directory)),
+        )?
         .end()?;
     Ok(())
 }
 
+/// Write the build edges which check the documentation of the project and check that it compiles
+/// with its minimum supported Rust version, if any, and return their outputs.
+///
+/// The latter is compiled in the `msrv` subdirectory of `target_directory`.
+fn write_doc_and_msrv_builds<W: Write>(
+    ninja_writer: &mut NinjaWriter<W>,
+    project: &Project,
+    cargo_lock: &str,
+    target_directory: &Utf8Path,
+) -> anyhow::Result<Vec<String>> {
+    let directory = &project.directory;
+    let doc_path = format!("{directory}/doc.ninjatarget");
+    ninja_writer
+        .build()?
+        .output(&doc_path)?
+        .rule("doc")?
+        .input(cargo_lock)?
+        .inputs(
+            iter::once(directory)
+                .chain(project.normal_dependencies.iter())
+                .map(|directory| format!("{directory}/fmt.ninjatarget")),
+        )?
+        .variable_and_value("project", &project.name)?
+        .end()?;
+    let Some(rust_version) = &project.rust_version else {
+        return Ok(vec![doc_path]);
+    };
+    let msrv_path = format!("{directory}/msrv.ninjatarget");
+    ninja_writer
+        .build()?
+        .output(&msrv_path)?
+        .rule("msrv")?
+        .input(cargo_lock)?
+        .inputs(
+            iter::once(directory)
+                .chain(project.normal_dependencies.iter())
+                .chain(project.dev_dependencies.iter())
+                .map(|directory| format!("{directory}/fmt.ninjatarget")),
+        )?
+        .variable_and_value("project", &project.name)?
+        .variable_and_value("rust_version", rust_version)?
+        .variable_and_value(
+            "msrv_target_dir",
+            quote_for_shell(target_directory.join("msrv").as_str().as_bytes()),
+        )?
+        .end()?;
+    Ok(vec![doc_path, msrv_path])
+}
+
+/// Write the build edges which compile the binaries of the project and copy them to `bin_path`,
+/// and return the paths of the copies.
+fn write_deploy_builds<W: Write>(
+    ninja_writer: &mut NinjaWriter<W>,
+    workspace: &Workspace,
+    project: &Project,
+    output_directory: &Utf8Path,
+    bin_path: &Path,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut deployed_paths = Vec::new();
+    if project.binaries_to_deploy.is_empty() {
+        return Ok(deployed_paths);
+    }
+    let directory = &project.directory;
+    // A single `cargo build` compiles all the binaries of the package.
+    let release_paths: Vec<Utf8PathBuf> =
+        project.binaries_to_deploy.iter().map(|binary| output_directory.join(binary)).collect();
+    let project_and_normal_dependencies: Vec<&String> =
+        iter::once(directory).chain(project.normal_dependencies.iter()).collect();
+    ninja_writer
+        .build()?
+        .output(&release_paths[0])?
+        .outputs(&release_paths[1..])?
+        .implicit_outputs(release_paths.iter().map(|path| format!("{path}.d")))?
+        .rule("compile")?
+        .input(workspace.path("Cargo.lock"))?
+        .inputs(
+            project_and_normal_dependencies
+                .iter()
+                .map(|directory| format!("{directory}/fmt.ninjatarget")),
+        )?
+        .variable_and_value("project", &project.name)?
+        .variable_and_value(
+            "cargo_depfiles",
+            join_for_shell(release_paths.iter().map(|path| format!("{path}.d"))),
+        )?
+        .variable_and_value("depfile", format!("{directory}/compile.ninjatarget.d"))?
+        .end()?;
+    for (binary, release_path) in project.binaries_to_deploy.iter().zip(&release_paths) {
+        let deployed_path = bin_path.join(binary);
+        ninja_writer
+            .build()?
+            .output(&deployed_path)?
+            .rule("copy")?
+            .input(release_path)?
+            .implicit_dependencies(
+                iter::once(project)
+                    .chain(workspace.projects.iter().filter(|dependency| {
+                        project.normal_dependencies.contains(&dependency.directory)
+                    }))
+                    .flat_map(Project::check_stamps),
+            )?
+            .order_only_dependency(bin_path)?
+            .end()?;
+        deployed_paths.push(deployed_path);
+    }
+    Ok(deployed_paths)
+}
+
+/// Write the build edge which makes Ninja write `build.ninja` and the files included with
+/// `subninja` again when a workspace changes:
+/// <https://ninja-build.org/manual.html#_generating_ninja_files_from_ninja>
+fn write_bootstrap_build<W: Write>(
+    ninja_writer: &mut NinjaWriter<W>,
+    workspaces: &[Workspace],
+    subninja_paths: &[String],
+) -> anyhow::Result<()> {
+    let mut inputs: Vec<PathBuf> = Vec::new();
+    let mut manifest_flags = None;
+    for workspace in workspaces {
+        inputs.push(workspace.path("Cargo.toml").into());
+        inputs.push(workspace.path("Cargo.lock").into());
+        inputs.extend(
+            workspace
+                .projects
+                .iter()
+                .map(|project| format!("{}/Cargo.toml", project.directory).into()),
+        );
+        // When `ninja_bootstrap` is not a member of a workspace, its sources are not known.
+        let projects = &workspace.projects;
+        if let Some(bootstrap) =
+            projects.iter().find(|project| project.name == env!("CARGO_PKG_NAME"))
+        {
+            for directory in iter::once(&bootstrap.directory).chain(&bootstrap.normal_dependencies)
+            {
+                inputs.extend(find_rust_sources(Path::new(directory))?);
+            }
+            if !workspace.directory.as_str().is_empty() {
+                manifest_flags = Some(workspace.manifest_flags());
+            }
+        }
+    }
+    let bootstrap_flags: Vec<Vec<u8>> = env::args_os()
+        .skip(1)
+        .map(|argument| quote_for_shell(argument.as_encoded_bytes()))
+        .collect();
+    let step = ninja_writer
+        .build()?
+        .output("build.ninja")?
+        .implicit_outputs(subninja_paths)?
+        .rule("bootstrap")?
+        .inputs(&inputs)?
+        .implicit_dependency("bootstrap.toml")?;
+    if bootstrap_flags.is_empty() {
+        step.end()?;
+    } else {
+        // `manifest_flags` is only set with `--workspace`, so with some flags.
+        let step = step.variable_and_value("bootstrap_flags", bootstrap_flags.join(&b' '))?;
+        match manifest_flags {
+            Some(manifest_flags) => {
+                step.variable_and_value("manifest_flags", manifest_flags)?.end()?;
+            }
+            None => step.end()?,
+        }
+    }
+    // `bootstrap.toml` is a dependency even if it is missing, so that creating it writes
+    // `build.ninja` again. Then this phony edge gives it the modification time of its input, which
+    // is older than `build.ninja`. Without input, the missing file would always be dirty, and so
+    // would `build.ninja`, even after being written again.
+    ninja_writer
+        .build()?
+        .output("bootstrap.toml")?
+        .rule("phony")?
+        .input(workspaces[0].path("Cargo.toml"))?
+        .end()?;
+    Ok(())
+}
+
+/// Get the sorted paths of the Rust files in the directory of a package, except in the hidden
+/// directories, the directories of other packages and the cache directories, like the target
+/// directory of cargo.
+fn find_rust_sources(directory: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut sources = Vec::new();
+    let mut directories = vec![directory.to_owned()];
+    while let Some(directory) = directories.pop() {
+        let context = || format!("failed to read the directory {}", quote_path(&directory));
+        for entry in fs::read_dir(&directory).with_context(context)? {
+            let entry = entry.with_context(context)?;
+            let path = entry.path();
+            if entry.file_name().as_encoded_bytes().starts_with(b".") {
+                continue;
+            }
+            if entry.file_type().with_context(context)?.is_dir() {
+                let is_skipped =
+                    ["Cargo.toml", "CACHEDIR.TAG"].iter().any(|name| path.join(name).exists());
+                if !is_skipped {
+                    directories.push(path);
+                }
+            } else if path.extension().is_some_and(|extension| extension == "rs") {
+                sources.push(path);
+            }
+        }
+    }
+    sources.sort();
+    Ok(sources)
+}
+
+/// Quote the paths for `sh` and join them with spaces, or return `/dev/null` if there is none.
+fn join_for_shell(paths: impl IntoIterator<Item = impl AsRef<str>>) -> Vec<u8> {
+    let paths: Vec<Vec<u8>> =
+        paths.into_iter().map(|path| quote_for_shell(path.as_ref().as_bytes())).collect();
+    if paths.is_empty() { b"/dev/null".to_vec() } else { paths.join(&b' ') }
+}
+
+/// Quote a command-line argument for `sh`.
+fn quote_for_shell(argument: &[u8]) -> Vec<u8> {
+    let is_safe = !argument.is_empty()
+        && argument.iter().all(|byte| byte.is_ascii_alphanumeric() || b"%+,-./:=@_".contains(byte));
+    if is_safe {
+        return argument.to_vec();
+    }
+    let mut result = vec![b'\''];
+    for &byte in argument {
+        if byte == b'\'' {
+            result.extend_from_slice(br"'\''");
+        } else {
+            result.push(byte);
+        }
+    }
+    result.push(b'\'');
+    result
+}
+
 fn get_cargo_target_dir() -> anyhow::Result<Utf8PathBuf> {
-    let metadata = MetadataCommand::new().exec().context("failed to execute metadata command")?;
+    let metadata =
+        MetadataCommand::new().no_deps().exec().context("failed to execute metadata command")?;
     Ok(metadata.target_directory)
 }
 
-fn get_local_dependencies(
-    project: &str,
-    local_projects: &[String],
-) -> anyhow::Result<Dependencies> {
-    let cargo_toml_path = format!("{project}/Cargo.toml");
-    (|| {
-        let cargo_toml = fs::read_to_string(&cargo_toml_path).context("failed to read the file")?;
-        let value = cargo_toml.parse::<Value>().context("invalid TOML")?;
-        let table = value.as_table().with_context(|| format!("not a table: {value:?}"))?;
-        let normal_dependencies = get_local_projects_from(table, "dependencies", local_projects)?;
-        let dev_dependencies = get_local_projects_from(table, "dev-dependencies", local_projects)?;
-        anyhow::Ok(Dependencies { normal_dependencies, dev_dependencies })
-    })()
-    .with_context(|| format!("error with {cargo_toml_path:?}"))
-}
-
-fn get_local_projects_from(
-    table: &Table,
-    key: &str,
-    local_projects: &[String],
-) -> anyhow::Result<Vec<String>> {
-    match table.get(key) {
-        Some(value) => {
-            let table =
-                value.as_table().with_context(|| format!("{key:?} is not a table: {value:?}"))?;
-            Ok(table.keys().filter(|name| local_projects.contains(name)).cloned().collect())
+/// Get the workspace members, sorted by directory, with their local dependencies.
+///
+/// The dependency graph comes from `cargo metadata`, so renamed packages and platform specific
+/// dependencies are taken into account. The directories of the members are prefixed with
+/// `workspace_directory`.
+fn get_projects(
+    metadata: &Metadata,
+    workspace_directory: &Utf8Path,
+) -> anyhow::Result<Vec<Project>> {
+    let resolve =
+        metadata.resolve.as_ref().context("cargo metadata did not resolve the dependencies")?;
+    let mut members: Vec<&Package> = metadata.workspace_packages();
+    let directories = members
+        .iter()
+        .map(|package| {
+            let directory = package
+                .manifest_path
+                .parent()
+                .and_then(|directory| directory.strip_prefix(&metadata.workspace_root).ok())
+                .with_context(|| {
+                    format!("{:?} is not in the workspace directory", package.manifest_path)
+                })?;
+            // Without trailing slash for the package at the root of the workspace
+            let directory: Utf8PathBuf = workspace_directory.join(directory).components().collect();
+            let directory =
+                if directory.as_str().is_empty() { ".".into() } else { directory.into_string() };
+            Ok((&package.id, directory))
+        })
+        .collect::<anyhow::Result<BTreeMap<&PackageId, String>>>()?;
+    members.sort_by(|package, other_package| {
+        directories[&package.id].cmp(&directories[&other_package.id])
+    });
+    // Direct local dependencies: needed to build, or only needed to check and test
+    let mut direct_dependencies: BTreeMap<&PackageId, (Vec<&PackageId>, Vec<&PackageId>)> =
+        BTreeMap::new();
+    for node in resolve.nodes.iter().filter(|node| directories.contains_key(&node.id)) {
+        let (normal_dependencies, dev_dependencies) =
+            direct_dependencies.entry(&node.id).or_default();
+        for dependency in
+            node.deps.iter().filter(|dependency| directories.contains_key(&dependency.pkg))
+        {
+            if dependency.dep_kinds.iter().any(|info| info.kind != DependencyKind::Development) {
+                normal_dependencies.push(&dependency.pkg);
+            } else {
+                dev_dependencies.push(&dependency.pkg);
+            }
+        }
+    }
+    members
+        .iter()
+        .map(|package| {
+            let (normal_dependencies, dev_dependencies) = &direct_dependencies[&package.id];
+            let normal_closure = get_closure(normal_dependencies, &direct_dependencies);
+            let mut dev_closure = get_closure(dev_dependencies, &direct_dependencies);
+            dev_closure.retain(|id| !normal_closure.contains(id));
+            let get_directories = |closure: &BTreeSet<&PackageId>| {
+                members
+                    .iter()
+                    .filter(|member| member.id != package.id && closure.contains(&member.id))
+                    .map(|member| directories[&member.id].clone())
+                    .collect()
+            };
+            Ok(Project {
+                name: package.name.clone(),
+                directory: directories[&package.id].clone(),
+                normal_dependencies: get_directories(&normal_closure),
+                dev_dependencies: get_directories(&dev_closure),
+                binaries_to_deploy: get_binaries_to_deploy(package)?,
+                depfile_names: get_depfile_names(package),
+                test_targets: get_test_targets(package),
+                rust_version: package.rust_version.as_ref().map(ToString::to_string),
+            })
+        })
+        .collect()
+}
+
+/// Get the given packages and the packages needed to build them, even indirectly.
+fn get_closure<'a>(
+    roots: &[&'a PackageId],
+    direct_dependencies: &BTreeMap<&'a PackageId, (Vec<&'a PackageId>, Vec<&'a PackageId>)>,
+) -> BTreeSet<&'a PackageId> {
+    let mut closure = BTreeSet::new();
+    let mut stack = roots.to_vec();
+    while let Some(id) = stack.pop() {
+        if closure.insert(id) {
+            if let Some((normal_dependencies, _)) = direct_dependencies.get(id) {
+                stack.extend(normal_dependencies);
+            }
         }
-        None => Ok(vec![]),
     }
+    closure
 }
 
-fn has_a_binary_to_deploy(project: &str) -> bool {
-    project != "ninja_bootstrap"
-        && (PathBuf::from(format!("{project}/src/main.rs")).is_file()
-            || PathBuf::from(format!("{project}/main.rs")).is_file())
+/// Get the names of the dependency files which `cargo build` writes next to the library and the
+/// binaries of the package.
+fn get_depfile_names(package: &Package) -> Vec<String> {
+    package
+        .targets
+        .iter()
+        .filter_map(|target| {
+            if target.is_bin() {
+                Some(format!("{}.d", target.name))
+            } else if target
+                .kind
+                .iter()
+                .any(|kind| matches!(kind.as_str(), "lib" | "rlib" | "dylib" | "proc-macro"))
+            {
+                Some(format!("lib{}.d", target.name.replace('-', "_")))
+            } else {
+                None
+            }
+        })
+        .collect()
 }
 
-#[derive(Deserialize)]
-struct CargoToml {
-    workspace: Workspace,
+/// Get the targets tested by `cargo test`, except the examples and the benchmarks.
+fn get_test_targets(package: &Package) -> Vec<TestTarget> {
+    let mut test_targets = Vec::new();
+    for target in &package.targets {
+        let name = &target.name;
+        let is_lib = target
+            .kind
+            .iter()
+            .any(|kind| matches!(kind.as_str(), "lib" | "rlib" | "dylib" | "proc-macro"));
+        if is_lib {
+            if target.test {
+                test_targets.push(TestTarget::new("test_lib", "--lib".into()));
+            }
+            if target.doctest {
+                test_targets.push(TestTarget::new("test_doc", "--doc".into()));
+            }
+        } else if target.is_bin() && target.test {
+            test_targets
+                .push(TestTarget::new(&format!("test_bin_{name}"), format!("--bin {name}")));
+        } else if target.is_test() && target.test {
+            test_targets.push(TestTarget::new(
+                &format!("test_integration_{name}"),
+                format!("--test {name}"),
+            ));
+        }
+    }
+    test_targets
 }
 
-#[derive(Deserialize)]
+/// Get the names of the binary targets of the package, unless the package has:
+///
+/// ```toml
+/// [package.metadata.bin_from_ninja]
+/// deploy = false
+/// ```
+fn get_binaries_to_deploy(package: &Package) -> anyhow::Result<Vec<String>> {
+    let deploy = &package.metadata["bin_from_ninja"]["deploy"];
+    let deploy = deploy.is_null()
+        || deploy.as_bool().with_context(|| {
+            format!(
+                "in the manifest of {}, package.metadata.bin_from_ninja.deploy is not a boolean",
+                package.name
+            )
+        })?;
+    Ok(if deploy {
+        package
+            .targets
+            .iter()
+            .filter(|target| target.is_bin())
+            .map(|target| target.name.clone())
+            .collect()
+    } else {
+        Vec::new()
+    })
+}
+
+/// Cargo workspace whose members are built by Ninja
 struct Workspace {
-    members: Vec<String>,
+    /// Directory relative to the directory of `build.ninja`, or empty if they are the same
+    directory: Utf8PathBuf,
+    /// Directory where cargo writes the compiled files
+    target_directory: Utf8PathBuf,
+    projects: Vec<Project>,
+}
+
+impl Workspace {
+    fn load(directory: Utf8PathBuf, offline: bool) -> anyhow::Result<Self> {
+        let mut metadata_command = MetadataCommand::new();
+        if !directory.as_str().is_empty() {
+            metadata_command.manifest_path(directory.join("Cargo.toml"));
+        }
+        if offline {
+            metadata_command.other_options(vec!["--offline".into()]);
+        }
+        let metadata = metadata_command.exec().with_context(|| {
+            format!(
+                "failed to execute metadata command in {}",
+                quote_path(Path::new(if directory.as_str().is_empty() {
+                    "."
+                } else {
+                    directory.as_str()
+                }))
+            )
+        })?;
+        let projects = get_projects(&metadata, &directory)?;
+        Ok(Self { directory, target_directory: metadata.target_directory, projects })
+    }
+
+    /// Get the cargo flags which select the workspace from the directory of `build.ninja`.
+    fn manifest_flags(&self) -> Vec<u8> {
+        [b"--manifest-path ".as_slice(), &quote_for_shell(self.path("Cargo.toml").as_bytes())]
+            .concat()
+    }
+
+    /// Get the path of a file of the workspace, or the name of a phony target of the workspace,
+    /// relative to the directory of `build.ninja`.
+    fn path(&self, name: &str) -> String {
+        self.directory.join(name).into_string()
+    }
 }
 
-struct Dependencies {
+/// Member of the workspace
+struct Project {
+    /// Package name, given to `cargo -p`
+    name: String,
+    /// Directory relative to the directory of `build.ninja`, where the stamp files are written
+    directory: String,
+    /// Directories of the local packages needed to build the project, even indirectly
     normal_dependencies: Vec<String>,
+    /// Directories of the other local packages needed to check or test the project
     dev_dependencies: Vec<String>,
+    /// Names of the binaries to copy to `~/bin`
+    binaries_to_deploy: Vec<String>,
+    /// Names of the dependency files written by `cargo build` in the profile directory
+    depfile_names: Vec<String>,
+    /// Targets tested by `cargo test`, each with its own stamp file
+    test_targets: Vec<TestTarget>,
+    /// Minimum supported Rust version, given to `cargo +<version>`
+    rust_version: Option<String>,
+}
+
+impl Project {
+    /// Get the paths of the stamp files written when clippy and the tests pass.
+    fn check_stamps(&self) -> impl Iterator<Item = String> {
+        iter::once(format!("{}/clippy.ninjatarget", self.directory)).chain(self.test_stamps())
+    }
+
+    /// Get the paths of the stamp files of the test targets.
+    fn test_stamps(&self) -> impl Iterator<Item = String> {
+        self.test_targets
+            .iter()
+            .map(|test_target| format!("{}/{}", self.directory, test_target.stamp_name))
+    }
+}
+
+/// Target tested by its own `cargo test` command
+struct TestTarget {
+    /// Name of the stamp file in the project directory, like `test_bin_backup.ninjatarget`
+    stamp_name: String,
+    /// Flags given to `cargo test` to select the target, like `--bin backup`
+    flags: String,
+}
+
+impl TestTarget {
+    fn new(name: &str, flags: String) -> Self {
+        Self { stamp_name: format!("{name}.ninjatarget"), flags }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::{Value, json};
+
+    #[test]
+    fn quote_for_shell_only_when_needed() {
+        assert_eq!(quote_for_shell(b"target/debug/lib_a.d"), b"target/debug/lib_a.d");
+        assert_eq!(quote_for_shell(b"--config=a@b+c,d:e%f"), b"--config=a@b+c,d:e%f");
+        assert_eq!(quote_for_shell(b""), b"''");
+        assert_eq!(quote_for_shell(b"my dir/$HOME"), b"'my dir/$HOME'");
+        assert_eq!(quote_for_shell(b"it's"), br"'it'\''s'");
+    }
+
+    #[test]
+    fn join_for_shell_or_dev_null() {
+        assert_eq!(join_for_shell(["a.d", "b c.d"]), b"a.d 'b c.d'");
+        assert_eq!(join_for_shell(Vec::<String>::new()), b"/dev/null");
+    }
+
+    #[test]
+    fn find_the_rust_sources_of_a_package() -> anyhow::Result<()> {
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let sources = find_rust_sources(directory)?;
+        assert!(sources.is_sorted());
+        assert!(sources.contains(&directory.join("main.rs")));
+        assert!(sources.contains(&directory.join("lib.rs")));
+        // The other members and the target directory are skipped.
+        let workspace_directory = directory.parent().context("no workspace directory")?;
+        assert_eq!(find_rust_sources(workspace_directory)?, Vec::<PathBuf>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn split_normal_and_dev_dependencies() -> anyhow::Result<()> {
+        // app → lib_a → lib_b
+        // app ⇢ helper → lib_c (dev dependency)
+        // app ⇢ lib_b (dev dependency, but already needed to build)
+        // lib_a → lib_c (normal and dev dependency)
+        let metadata = example_metadata(
+            [
+                package_json("app", "app", [bin_target("app")], &Value::Null),
+                package_json("helper", "helper", [lib_target("helper")], &Value::Null),
+                package_json("lib_a", "lib_a", [lib_target("lib_a")], &Value::Null),
+                package_json("lib_b", "lib_b", [lib_target("lib_b")], &Value::Null),
+                package_json("lib_c", "lib_c", [lib_target("lib_c")], &Value::Null),
+                package_json("root", "", [bin_target("root")], &Value::Null),
+            ],
+            [
+                node(
+                    "app",
+                    "app",
+                    [
+                        dep("lib_a", [None]),
+                        dep("helper", [Some("dev")]),
+                        dep("lib_b", [Some("dev")]),
+                    ],
+                ),
+                node("helper", "helper", [dep("lib_c", [None])]),
+                node("lib_a", "lib_a", [dep("lib_b", [None]), dep("lib_c", [None, Some("dev")])]),
+                node("lib_b", "lib_b", []),
+                node("lib_c", "lib_c", []),
+                node("root", "", []),
+            ],
+        )?;
+        let projects = get_projects(&metadata, Utf8Path::new("sub"))?;
+        let directories: Vec<&str> =
+            projects.iter().map(|project| project.directory.as_str()).collect();
+        assert_eq!(
+            directories,
+            ["sub", "sub/app", "sub/helper", "sub/lib_a", "sub/lib_b", "sub/lib_c"]
+        );
+        let app = &projects[1];
+        assert_eq!(app.name, "app");
+        assert_eq!(app.normal_dependencies, ["sub/lib_a", "sub/lib_b", "sub/lib_c"]);
+        assert_eq!(app.dev_dependencies, ["sub/helper"]);
+        let lib_a = &projects[3];
+        assert_eq!(lib_a.normal_dependencies, ["sub/lib_b", "sub/lib_c"]);
+        assert!(lib_a.dev_dependencies.is_empty());
+        let projects = get_projects(&metadata, Utf8Path::new(""))?;
+        assert_eq!(projects[0].directory, ".");
+        assert_eq!(projects[0].depfile_names, ["root.d"]);
+        Ok(())
+    }
+
+    #[test]
+    fn get_the_test_targets() -> anyhow::Result<()> {
+        let package: Package = serde_json::from_value(package_json(
+            "app",
+            "app",
+            [
+                lib_target("app-core"),
+                bin_target("app"),
+                json!({"name": "untested", "kind": ["bin"], "src_path": "/ws/u.rs", "test": false}),
+                json!({"name": "cli", "kind": ["test"], "src_path": "/ws/app/tests/cli.rs"}),
+                json!({"name": "demo", "kind": ["example"], "src_path": "/ws/app/examples/d.rs"}),
+                json!({"name": "speed", "kind": ["bench"], "src_path": "/ws/app/benches/s.rs"}),
+            ],
+            &Value::Null,
+        ))?;
+        let test_targets = get_test_targets(&package);
+        let test_targets: Vec<(&str, &str)> = test_targets
+            .iter()
+            .map(|test_target| (test_target.stamp_name.as_str(), test_target.flags.as_str()))
+            .collect();
+        assert_eq!(
+            test_targets,
+            [
+                ("test_lib.ninjatarget", "--lib"),
+                ("test_doc.ninjatarget", "--doc"),
+                ("test_bin_app.ninjatarget", "--bin app"),
+                ("test_integration_cli.ninjatarget", "--test cli"),
+            ]
+        );
+        assert_eq!(get_depfile_names(&package), ["libapp_core.d", "app.d", "untested.d"]);
+        Ok(())
+    }
+
+    #[test]
+    fn get_the_binaries_to_deploy() -> anyhow::Result<()> {
+        let targets = || [lib_target("app"), bin_target("app"), bin_target("app-admin")];
+        let package: Package =
+            serde_json::from_value(package_json("app", "app", targets(), &Value::Null))?;
+        assert_eq!(get_binaries_to_deploy(&package)?, ["app", "app-admin"]);
+        let metadata = json!({"bin_from_ninja": {"deploy": true}});
+        let package: Package =
+            serde_json::from_value(package_json("app", "app", targets(), &metadata))?;
+        assert_eq!(get_binaries_to_deploy(&package)?, ["app", "app-admin"]);
+        let metadata = json!({"bin_from_ninja": {"deploy": false}});
+        let package: Package =
+            serde_json::from_value(package_json("app", "app", targets(), &metadata))?;
+        assert!(get_binaries_to_deploy(&package)?.is_empty());
+        let metadata = json!({"bin_from_ninja": {"deploy": "no"}});
+        let package: Package =
+            serde_json::from_value(package_json("app", "app", targets(), &metadata))?;
+        let error = get_binaries_to_deploy(&package).unwrap_err();
+        assert!(error.to_string().contains("deploy is not a boolean"), "{error}");
+        Ok(())
+    }
+
+    /// Get the metadata of a workspace in `/ws` whose members are the given packages.
+    fn example_metadata(
+        packages: impl IntoIterator<Item = Value>,
+        nodes: impl IntoIterator<Item = Value>,
+    ) -> serde_json::Result<Metadata> {
+        let packages: Vec<Value> = packages.into_iter().collect();
+        let members: Vec<Value> = packages.iter().map(|package| package["id"].clone()).collect();
+        serde_json::from_value(json!({
+            "packages": packages,
+            "workspace_members": members,
+            "resolve": {"nodes": nodes.into_iter().collect::<Vec<_>>(), "root": null},
+            "workspace_root": "/ws",
+            "target_directory": "/ws/target",
+            "version": 1,
+        }))
+    }
+
+    /// Get the JSON of a package in the `directory` of the workspace, like `cargo metadata`.
+    fn package_json(
+        name: &str,
+        directory: &str,
+        targets: impl IntoIterator<Item = Value>,
+        metadata: &Value,
+    ) -> Value {
+        json!({
+            "name": name,
+            "version": "0.1.0",
+            "id": package_id(name, directory),
+            "dependencies": [],
+            "targets": targets.into_iter().collect::<Vec<_>>(),
+            "features": {},
+            "manifest_path": Utf8Path::new("/ws").join(directory).join("Cargo.toml"),
+            "metadata": metadata,
+        })
+    }
+
+    fn package_id(name: &str, directory: &str) -> String {
+        format!("path+file:///ws/{directory}#{name}@0.1.0")
+    }
+
+    fn lib_target(name: &str) -> Value {
+        let src_path = format!("/ws/{name}/lib.rs");
+        json!({"name": name.replace('-', "_"), "kind": ["lib"], "src_path": src_path})
+    }
+
+    fn bin_target(name: &str) -> Value {
+        json!({"name": name, "kind": ["bin"], "src_path": format!("/ws/{name}/main.rs")})
+    }
+
+    /// Get the JSON of a node of the dependency graph. The dependencies are in the directory named
+    /// like the package.
+    fn node(name: &str, directory: &str, deps: impl IntoIterator<Item = Value>) -> Value {
+        let deps: Vec<Value> = deps.into_iter().collect();
+        json!({"id": package_id(name, directory), "deps": deps, "dependencies": []})
+    }
+
+    /// Get the JSON of a dependency with the given kinds: `None` for normal, `Some("dev")`, etc.
+    fn dep<const N: usize>(name: &str, kinds: [Option<&str>; N]) -> Value {
+        let dep_kinds: Vec<Value> =
+            kinds.iter().map(|kind| json!({"kind": kind, "target": null})).collect();
+        json!({"name": name, "pkg": package_id(name, name), "dep_kinds": dep_kinds})
+    }
 }
@@ -0,0 +1,99 @@
+//! Write a Ninja build file to an asynchronous writer
+//!
+//! [`AsyncNinjaWriter`] writes the definitions in a buffer with a [`NinjaWriter`], so with the same
+//! typestate, escaping and wrapping, then writes the buffer to any [`AsyncWrite`] when
+//! [`AsyncNinjaWriter::flush`] is awaited. The typestate borrows the [`NinjaWriter`], so a
+//! definition cannot be flushed before its end.
+//!
+//! Nothing is written to the [`AsyncWrite`] before a flush: the whole file stays in memory unless
+//! the caller flushes after each definition or group of definitions.
+//!
+//! ```
+//! # use ninja_bootstrap::ninja_async_writer::AsyncNinjaWriter;
+//! # use ninja_bootstrap::ninja_writer::{Config, Error, Expr};
+//! # async fn example(socket: Vec<u8>) -> Result<Vec<u8>, Error> {
+//! let mut ninja_writer = AsyncNinjaWriter::new(Config::with_width(80), socket);
+//! let command = Expr::new().text("cp -- ").variable("in").text(" ").variable("out");
+//! ninja_writer.definitions().rule("copy")?.command(command)?.end()?;
+//! ninja_writer.flush().await?;
+//! # Ok(ninja_writer.into_inner())
+//! # }
+//! # let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+//! # let output = runtime.block_on(example(Vec::new())).unwrap();
+//! # assert_eq!(output, b"rule copy\n  command = cp -- $in $out\n");
+//! ```
+
+use snafu::ResultExt as _;
+use tokio::io::{AsyncWrite, AsyncWriteExt as _};
+
+use crate::ninja_writer::{Config, Error, FlushSnafu, NinjaWriter};
+
+pub struct AsyncNinjaWriter<W: AsyncWrite + Unpin> {
+    ninja_writer: NinjaWriter<Vec<u8>>,
+    writer: W,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncNinjaWriter<W> {
+    pub const fn new(config: Config, writer: W) -> Self {
+        Self { ninja_writer: NinjaWriter::new(config, Vec::new()), writer }
+    }
+
+    /// Get the writer of the definitions, which are buffered until the next flush.
+    ///
+    /// Writing in the buffer cannot fail with an I/O error, but the other errors of
+    /// [`NinjaWriter`] remain, like an unrepresentable path.
+    pub const fn definitions(&mut self) -> &mut NinjaWriter<Vec<u8>> {
+        &mut self.ninja_writer
+    }
+
+    /// Write the buffered definitions and flush the writer.
+    ///
+    /// If this fails, an unknown part of the buffer may have been written.
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        let buffer = self.ninja_writer.writer_mut();
+        self.writer.write_all(buffer).await.context(FlushSnafu)?;
+        buffer.clear();
+        self.writer.flush().await.context(FlushSnafu)?;
+        Ok(())
+    }
+
+    /// Get the asynchronous writer back, without the definitions which have not been flushed.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::runtime;
+
+    use crate::ninja_writer::Expr;
+
+    fn write_definitions(ninja_writer: &mut NinjaWriter<Vec<u8>>) -> Result<(), Error> {
+        ninja_writer.variable("flags", " -O2 $x")?;
+        ninja_writer
+            .rule("cc")?
+            .command(Expr::new().text("cc ").variable("flags").text(" -c ").variable("in"))?
+            .end()?;
+        ninja_writer.build()?.output("a b.o")?.rule("cc")?.input("a.c")?.end()?;
+        Ok(())
+    }
+
+    #[test]
+    fn write_like_the_synchronous_writer() {
+        let mut expected = NinjaWriter::new(Config::with_width(30), Vec::new());
+        write_definitions(&mut expected).unwrap();
+        let output = runtime::Builder::new_current_thread().build().unwrap().block_on(async {
+            let mut ninja_writer = AsyncNinjaWriter::new(Config::with_width(30), Vec::new());
+            write_definitions(ninja_writer.definitions()).unwrap();
+            ninja_writer.flush().await.unwrap();
+            ninja_writer.definitions().default("a b.o")?.end()?;
+            ninja_writer.flush().await?;
+            Ok::<_, Error>(ninja_writer.into_inner())
+        });
+        expected.default("a b.o").unwrap().end().unwrap();
+        assert_eq!(output.unwrap(), expected.writer_mut().as_slice());
+    }
+}
@@ -0,0 +1,256 @@
+//! Compare two Ninja files statement by statement
+//!
+//! The order of the statements does not matter: rules, pools and top-level variables are
+//! identified by their name, build edges by their explicit outputs (or implicit outputs if there is
+//! no explicit output) and the `default` statements are merged together.
+//!
+//! Before comparing two files generated on different machines, `normalize` replaces the machine
+//! dependent paths, like the home directory, with variable references.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+
+use crate::ninja_parser::{Binding, EvalString, NinjaFile, Piece, Statement, is_identifier_byte};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    Added(Statement),
+    Removed(Statement),
+    Changed { old: Statement, new: Statement },
+}
+
+/// What identifies a statement in a Ninja file
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Key {
+    Variable(String),
+    Pool(String),
+    Rule(String),
+    Build(Vec<EvalString>),
+    Default,
+    Include(EvalString),
+    Subninja(EvalString),
+}
+
+/// Replace each occurrence of a path in the literal parts of the file with a reference to a
+/// variable, like `/home/denis` with `$HOME`.
+///
+/// An occurrence must not be followed by a byte of a variable name, so `/home/denis` is not
+/// replaced in `/home/denis2`. Longer paths are replaced first, so a cargo target directory in the
+/// home directory is replaced before the home directory.
+pub fn normalize(file: &mut NinjaFile, replacements: &[(&[u8], &str)]) {
+    let mut replacements = replacements.to_vec();
+    replacements.sort_by_key(|(path, _)| std::cmp::Reverse(path.len()));
+    for eval_string in file.statements.iter_mut().flat_map(eval_strings_mut) {
+        for (path, variable) in &replacements {
+            eval_string.0 = eval_string
+                .0
+                .drain(..)
+                .flat_map(|piece| match piece {
+                    Piece::Literal(literal) => replace(&literal, path, variable),
+                    Piece::Variable(_) => vec![piece],
+                })
+                .collect();
+        }
+    }
+}
+
+/// Compare the statements of two files, after sorting their bindings by name
+#[must_use]
+pub fn diff(old: &NinjaFile, new: &NinjaFile) -> Vec<Change> {
+    let mut old = index(old);
+    let new = index(new);
+    let mut changes = Vec::new();
+    for (key, new_statement) in new {
+        match old.remove(&key) {
+            None => changes.push((key, Change::Added(new_statement))),
+            Some(old_statement) if old_statement != new_statement => {
+                changes.push((key, Change::Changed { old: old_statement, new: new_statement }));
+            }
+            Some(_) => {}
+        }
+    }
+    changes.extend(old.into_iter().map(|(key, statement)| (key, Change::Removed(statement))));
+    changes.sort_by(|(key, _), (other_key, _)| key.cmp(other_key));
+    changes.into_iter().map(|(_, change)| change).collect()
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Added(statement) => {
+                writeln!(f, "added {}:", describe(statement))?;
+                write_lines(f, "+ ", statement)
+            }
+            Self::Removed(statement) => {
+                writeln!(f, "removed {}:", describe(statement))?;
+                write_lines(f, "- ", statement)
+            }
+            Self::Changed { old, new } => {
+                writeln!(f, "changed {}:", describe(new))?;
+                write_lines(f, "- ", old)?;
+                write_lines(f, "+ ", new)
+            }
+        }
+    }
+}
+
+fn write_lines(formatter: &mut Formatter<'_>, prefix: &str, statement: &Statement) -> fmt::Result {
+    statement.to_string().lines().try_for_each(|line| writeln!(formatter, "{prefix}{line}"))
+}
+
+fn describe(statement: &Statement) -> String {
+    match statement {
+        Statement::Variable(binding) => format!("variable {}", binding.name),
+        Statement::Pool(pool) => format!("pool {}", pool.name),
+        Statement::Rule(rule) => format!("rule {}", rule.name),
+        Statement::Build(build) => {
+            let outputs =
+                if build.outputs.is_empty() { &build.implicit_outputs } else { &build.outputs };
+            let outputs: Vec<String> = outputs.iter().map(ToString::to_string).collect();
+            format!("build edge of {}", outputs.join(" "))
+        }
+        Statement::Default(_) => "default targets".into(),
+        Statement::Include(path) => format!("include of {path}"),
+        Statement::Subninja(path) => format!("subninja of {path}"),
+    }
+}
+
+fn index(file: &NinjaFile) -> BTreeMap<Key, Statement> {
+    let mut result = BTreeMap::new();
+    let mut default_targets = Vec::new();
+    for statement in &file.statements {
+        let mut statement = statement.clone();
+        let key = match &mut statement {
+            Statement::Variable(binding) => Key::Variable(binding.name.clone()),
+            Statement::Pool(pool) => {
+                sort_bindings(&mut pool.bindings);
+                Key::Pool(pool.name.clone())
+            }
+            Statement::Rule(rule) => {
+                sort_bindings(&mut rule.bindings);
+                Key::Rule(rule.name.clone())
+            }
+            Statement::Build(build) => {
+                sort_bindings(&mut build.bindings);
+                if build.outputs.is_empty() {
+                    Key::Build(build.implicit_outputs.clone())
+                } else {
+                    Key::Build(build.outputs.clone())
+                }
+            }
+            Statement::Default(targets) => {
+                default_targets.append(targets);
+                continue;
+            }
+            Statement::Include(path) => Key::Include(path.clone()),
+            Statement::Subninja(path) => Key::Subninja(path.clone()),
+        };
+        result.insert(key, statement);
+    }
+    if !default_targets.is_empty() {
+        default_targets.sort();
+        default_targets.dedup();
+        result.insert(Key::Default, Statement::Default(default_targets));
+    }
+    result
+}
+
+/// Sort by name, but keep the order of the bindings with the same name, because the last one wins.
+fn sort_bindings(bindings: &mut [Binding]) {
+    bindings.sort_by(|binding, other_binding| binding.name.cmp(&other_binding.name));
+}
+
+fn eval_strings_mut(statement: &mut Statement) -> Vec<&mut EvalString> {
+    match statement {
+        Statement::Variable(binding) => vec![&mut binding.value],
+        Statement::Pool(pool) => {
+            pool.bindings.iter_mut().map(|binding| &mut binding.value).collect()
+        }
+        Statement::Rule(rule) => {
+            rule.bindings.iter_mut().map(|binding| &mut binding.value).collect()
+        }
+        Statement::Build(build) => build
+            .outputs
+            .iter_mut()
+            .chain(&mut build.implicit_outputs)
+            .chain(&mut build.inputs)
+            .chain(&mut build.implicit_dependencies)
+            .chain(&mut build.order_only_dependencies)
+            .chain(&mut build.validations)
+            .chain(build.bindings.iter_mut().map(|binding| &mut binding.value))
+            .collect(),
+        Statement::Default(targets) => targets.iter_mut().collect(),
+        Statement::Include(path) | Statement::Subninja(path) => vec![path],
+    }
+}
+
+fn replace(literal: &[u8], path: &[u8], variable: &str) -> Vec<Piece> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    let mut position = 0;
+    while position < literal.len() {
+        let rest = &literal[position..];
+        let is_match = rest.starts_with(path)
+            && !path.is_empty()
+            && !rest.get(path.len()).is_some_and(|&byte| is_identifier_byte(byte));
+        if is_match {
+            if start < position {
+                pieces.push(Piece::Literal(literal[start..position].to_vec()));
+            }
+            pieces.push(Piece::Variable(variable.into()));
+            position += path.len();
+            start = position;
+        } else {
+            position += 1;
+        }
+    }
+    if start < literal.len() {
+        pieces.push(Piece::Literal(literal[start..].to_vec()));
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ninja_parser::parse;
+
+    #[test]
+    fn ignore_the_order_of_the_statements_and_of_the_bindings() {
+        let old = b"rule cc\n  command = cc $in\n  description = cc\nbuild a: cc b\ndefault a\n";
+        let new = b"default a\nbuild a: cc b\nrule cc\n  description = cc\n  command = cc $in\n";
+        assert_eq!(diff(&parse(old).unwrap(), &parse(new).unwrap()), vec![]);
+    }
+
+    #[test]
+    fn report_added_removed_and_changed_statements() {
+        let old = parse(b"rule cc\n  command = cc $in\nbuild a: cc b\nbuild c: cc d\n").unwrap();
+        let new =
+            parse(b"rule cc\n  command = cc -O2 $in\nbuild a: cc b\nbuild e: cc f\n").unwrap();
+        let changes: Vec<String> = diff(&old, &new).iter().map(ToString::to_string).collect();
+        assert_eq!(
+            changes,
+            [
+                "changed rule cc:\n- rule cc\n-   command = cc $in\n+ rule cc\n+   command = cc -O2 $in\n",
+                "removed build edge of c:\n- build c: cc d\n",
+                "added build edge of e:\n+ build e: cc f\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn normalize_machine_dependent_paths() {
+        let mut old = parse(b"build /home/denis/bin/a: cp /home/denis/git/target/a\n").unwrap();
+        let mut new = parse(b"build /home/bob/bin/a: cp /tmp/target/a /home/bob2\n").unwrap();
+        normalize(&mut old, &[(b"/home/denis", "HOME"), (b"/home/denis/git/target", "TARGET")]);
+        normalize(&mut new, &[(b"/home/bob", "HOME"), (b"/tmp/target", "TARGET")]);
+        let changes: Vec<String> = diff(&old, &new).iter().map(ToString::to_string).collect();
+        assert_eq!(
+            changes,
+            ["changed build edge of $HOME/bin/a:\n\
+              - build $HOME/bin/a: cp $TARGET/a\n\
+              + build $HOME/bin/a: cp $TARGET/a /home/bob2\n"]
+        );
+    }
+}
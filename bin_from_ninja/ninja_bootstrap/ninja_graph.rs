@@ -0,0 +1,350 @@
+//! Dependency graph of the build edges of a Ninja file
+//!
+//! The edges are recorded by [`NinjaWriter`] while it writes the build definitions, so the graph is
+//! the one of the written files, without parsing them again. It can be written as JSON or in the
+//! DOT language of [Graphviz][], where each node is colored by the rule of the build edge which
+//! produces it.
+//!
+//! The variables of an edge are the ones of its build definition, whose references are written as
+//! `${name}` and not evaluated, so the variables of the file of the edge are not mixed with the ones
+//! of other files. The bytes which are not valid UTF-8 are replaced with `U+FFFD`.
+//!
+//! [Graphviz]: https://graphviz.org/
+//! [`NinjaWriter`]: crate::ninja_writer::NinjaWriter
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+
+use serde::Serialize;
+
+/// Colors of the nodes produced by each rule, in the alphabetical order of the rules
+const RULE_COLORS: [&str; 16] = [
+    "lightblue",
+    "palegreen",
+    "lightpink",
+    "khaki",
+    "plum",
+    "lightsalmon",
+    "paleturquoise",
+    "wheat",
+    "lightgray",
+    "lightgoldenrod",
+    "lightsteelblue",
+    "peachpuff",
+    "thistle",
+    "darkseagreen1",
+    "lightyellow",
+    "mistyrose",
+];
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Graph {
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Node {
+    pub path: String,
+    /// Rule of the build edge which produces the node, or `None` for a source file
+    pub rule: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Edge {
+    pub rule: String,
+    pub outputs: Vec<String>,
+    pub implicit_outputs: Vec<String>,
+    pub inputs: Vec<String>,
+    pub implicit_dependencies: Vec<String>,
+    pub order_only_dependencies: Vec<String>,
+    pub validations: Vec<String>,
+    pub variables: BTreeMap<String, String>,
+}
+
+impl Graph {
+    /// Get the graph of the build edges, with the nodes sorted by path
+    #[must_use]
+    pub fn new(edges: Vec<Edge>) -> Self {
+        let mut rules: BTreeMap<&str, Option<&str>> = BTreeMap::new();
+        for edge in &edges {
+            for path in edge.dependencies() {
+                rules.entry(path).or_default();
+            }
+            for path in edge.outputs.iter().chain(&edge.implicit_outputs) {
+                rules.insert(path, Some(&edge.rule));
+            }
+        }
+        let nodes = rules
+            .into_iter()
+            .map(|(path, rule)| Node { path: path.into(), rule: rule.map(Into::into) })
+            .collect();
+        Self { nodes, edges }
+    }
+
+    /// Write the graph as pretty-printed JSON.
+    ///
+    /// # Errors
+    ///
+    /// Fail if writing to `out` fails.
+    pub fn write_json<W: Write>(&self, mut out: W) -> io::Result<()> {
+        serde_json::to_writer_pretty(&mut out, self)?;
+        writeln!(out)
+    }
+
+    /// Write the graph in the DOT language of Graphviz, with a legend of the colors of the rules.
+    ///
+    /// An arrow goes from each dependency to each output of a build edge. It is dashed for an
+    /// implicit dependency or an implicit output and dotted for an order-only dependency. A
+    /// validation is linked to each output by a dotted arrow with a circle head.
+    ///
+    /// # Errors
+    ///
+    /// Fail if writing to `out` fails.
+    pub fn write_dot<W: Write>(&self, mut out: W) -> io::Result<()> {
+        let rules: BTreeSet<&str> = self.edges.iter().map(|edge| edge.rule.as_str()).collect();
+        let color_of = |rule: &str| {
+            let index = rules.iter().position(|&other_rule| other_rule == rule).unwrap_or(0);
+            RULE_COLORS[index % RULE_COLORS.len()]
+        };
+        writeln!(out, "digraph ninja {{")?;
+        writeln!(out, "  rankdir = LR;")?;
+        writeln!(out, "  node [shape = box, style = filled, fillcolor = white];")?;
+        writeln!(out, "  subgraph cluster_legend {{")?;
+        writeln!(out, "    label = \"rules\";")?;
+        for rule in &rules {
+            let id = quote(&format!("rule {rule}"));
+            writeln!(out, "    {id} [label = {}, fillcolor = {}];", quote(rule), color_of(rule))?;
+        }
+        writeln!(out, "  }}")?;
+        for node in &self.nodes {
+            match &node.rule {
+                Some(rule) => writeln!(
+                    out,
+                    "  {} [fillcolor = {}, tooltip = {}];",
+                    quote(&node.path),
+                    color_of(rule),
+                    quote(rule)
+                )?,
+                None => writeln!(out, "  {};", quote(&node.path))?,
+            }
+        }
+        for edge in &self.edges {
+            let outputs =
+                edge.outputs.iter().map(|output| (output, "")).chain(
+                    edge.implicit_outputs.iter().map(|output| (output, " [style = dashed]")),
+                );
+            for (output, output_style) in outputs {
+                let dependencies = edge
+                    .inputs
+                    .iter()
+                    .map(|input| (input, output_style))
+                    .chain(
+                        edge.implicit_dependencies.iter().map(|input| (input, " [style = dashed]")),
+                    )
+                    .chain(
+                        edge.order_only_dependencies
+                            .iter()
+                            .map(|input| (input, " [style = dotted]")),
+                    );
+                for (input, style) in dependencies {
+                    writeln!(out, "  {} -> {}{style};", quote(input), quote(output))?;
+                }
+                for validation in &edge.validations {
+                    writeln!(
+                        out,
+                        "  {} -> {} [style = dotted, arrowhead = odot];",
+                        quote(output),
+                        quote(validation)
+                    )?;
+                }
+            }
+        }
+        writeln!(out, "}}")
+    }
+}
+
+impl Edge {
+    fn dependencies(&self) -> impl Iterator<Item = &str> {
+        self.inputs
+            .iter()
+            .chain(&self.implicit_dependencies)
+            .chain(&self.order_only_dependencies)
+            .chain(&self.validations)
+            .map(String::as_str)
+    }
+}
+
+/// Quote a DOT identifier.
+fn quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ninja_writer::{Config, Expr, NinjaWriter};
+
+    /// Get the edges of `build a.o | a.d: cc a.c | a.h || gen` and `build all: phony a.o`.
+    fn example_edges() -> Vec<Edge> {
+        let mut ninja_writer = NinjaWriter::new(Config::with_width(80), Vec::new());
+        ninja_writer.record_edges(Vec::new());
+        ninja_writer
+            .build()
+            .unwrap()
+            .output("a.o")
+            .unwrap()
+            .implicit_output("a.d")
+            .unwrap()
+            .rule("cc")
+            .unwrap()
+            .input("a.c")
+            .unwrap()
+            .implicit_dependency("a.h")
+            .unwrap()
+            .order_only_dependency("gen")
+            .unwrap()
+            .variable_and_value("flags", "-O2")
+            .unwrap()
+            .end()
+            .unwrap();
+        ninja_writer
+            .build()
+            .unwrap()
+            .output("all")
+            .unwrap()
+            .rule("phony")
+            .unwrap()
+            .input("a.o")
+            .unwrap()
+            .end()
+            .unwrap();
+        ninja_writer.take_recorded_edges().unwrap()
+    }
+
+    #[test]
+    fn color_each_node_with_the_rule_which_produces_it() {
+        let graph = Graph::new(example_edges());
+        let nodes: Vec<(&str, Option<&str>)> =
+            graph.nodes.iter().map(|node| (node.path.as_str(), node.rule.as_deref())).collect();
+        assert_eq!(
+            nodes,
+            [
+                ("a.c", None),
+                ("a.d", Some("cc")),
+                ("a.h", None),
+                ("a.o", Some("cc")),
+                ("all", Some("phony")),
+                ("gen", None),
+            ]
+        );
+        assert_eq!(graph.edges[0].variables, BTreeMap::from([("flags".into(), "-O2".into())]));
+    }
+
+    #[test]
+    fn keep_the_variables_of_each_edge_across_files() {
+        let mut ninja_writer = NinjaWriter::new(Config::with_width(80), Vec::new());
+        ninja_writer.record_edges(Vec::new());
+        let mut subninja_writer = NinjaWriter::new(Config::with_width(80), Vec::new());
+        subninja_writer.record_edges(ninja_writer.take_recorded_edges().unwrap());
+        subninja_writer.variable("flags", "-O2").unwrap();
+        subninja_writer
+            .build()
+            .unwrap()
+            .output("a/stamp")
+            .unwrap()
+            .rule("touch")
+            .unwrap()
+            .variable_and_value("project", Expr::new().text("a ").variable("flags"))
+            .unwrap()
+            .end()
+            .unwrap();
+        ninja_writer.record_edges(subninja_writer.take_recorded_edges().unwrap());
+        ninja_writer.variable("flags", "-O0").unwrap();
+        ninja_writer
+            .build()
+            .unwrap()
+            .output("b/stamp")
+            .unwrap()
+            .rule("touch")
+            .unwrap()
+            .end()
+            .unwrap();
+        let graph = Graph::new(ninja_writer.take_recorded_edges().unwrap());
+        let variables: Vec<&BTreeMap<String, String>> =
+            graph.edges.iter().map(|edge| &edge.variables).collect();
+        assert_eq!(
+            variables,
+            [&BTreeMap::from([("project".into(), "a ${flags}".into())]), &BTreeMap::new()]
+        );
+    }
+
+    #[test]
+    fn write_json() {
+        let mut ninja_writer = NinjaWriter::new(Config::with_width(80), Vec::new());
+        ninja_writer.record_edges(Vec::new());
+        ninja_writer
+            .build()
+            .unwrap()
+            .output("all")
+            .unwrap()
+            .rule("phony")
+            .unwrap()
+            .input("a b")
+            .unwrap()
+            .end()
+            .unwrap();
+        let graph = Graph::new(ninja_writer.take_recorded_edges().unwrap());
+        let mut json = Vec::new();
+        graph.write_json(&mut json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "nodes": [{"path": "a b", "rule": null}, {"path": "all", "rule": "phony"}],
+                "edges": [{
+                    "rule": "phony",
+                    "outputs": ["all"],
+                    "implicit_outputs": [],
+                    "inputs": ["a b"],
+                    "implicit_dependencies": [],
+                    "order_only_dependencies": [],
+                    "validations": [],
+                    "variables": {},
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn write_dot() {
+        let mut dot = Vec::new();
+        Graph::new(example_edges()).write_dot(&mut dot).unwrap();
+        assert_eq!(
+            String::from_utf8(dot).unwrap(),
+            "digraph ninja {\n\
+            \x20 rankdir = LR;\n\
+            \x20 node [shape = box, style = filled, fillcolor = white];\n\
+            \x20 subgraph cluster_legend {\n\
+            \x20   label = \"rules\";\n\
+            \x20   \"rule cc\" [label = \"cc\", fillcolor = lightblue];\n\
+            \x20   \"rule phony\" [label = \"phony\", fillcolor = palegreen];\n\
+            \x20 }\n\
+            \x20 \"a.c\";\n\
+            \x20 \"a.d\" [fillcolor = lightblue, tooltip = \"cc\"];\n\
+            \x20 \"a.h\";\n\
+            \x20 \"a.o\" [fillcolor = lightblue, tooltip = \"cc\"];\n\
+            \x20 \"all\" [fillcolor = palegreen, tooltip = \"phony\"];\n\
+            \x20 \"gen\";\n\
+            \x20 \"a.c\" -> \"a.o\";\n\
+            \x20 \"a.h\" -> \"a.o\" [style = dashed];\n\
+            \x20 \"gen\" -> \"a.o\" [style = dotted];\n\
+            \x20 \"a.c\" -> \"a.d\" [style = dashed];\n\
+            \x20 \"a.h\" -> \"a.d\" [style = dashed];\n\
+            \x20 \"gen\" -> \"a.d\" [style = dotted];\n\
+            \x20 \"a.o\" -> \"all\";\n\
+            }\n"
+        );
+    }
+}
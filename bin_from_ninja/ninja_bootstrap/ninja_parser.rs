@@ -0,0 +1,1071 @@
+//! Parse a Ninja build file into a syntax tree
+//!
+//! The lexer follows the one of Ninja: <https://ninja-build.org/manual.html#ref_lexer>
+//!
+//! Variables are not evaluated and rules are not resolved: the syntax tree keeps what is written
+//! in the file. This is enough to check what `NinjaWriter` writes or to compare two files.
+//!
+//! `Statement` implements `Display`, which writes the statement back in the Ninja syntax, without
+//! line continuations.
+
+use std::fmt::{self, Display, Formatter};
+
+use snafu::{Snafu, ensure};
+
+// Opaque error type: https://docs.rs/snafu/0.8.0/snafu/guide/opaque/index.html
+#[derive(Debug, Snafu)]
+pub struct Error(InnerError);
+
+#[derive(Debug, Snafu)]
+enum InnerError {
+    #[snafu(display("line {line}: expected {expected}"))]
+    Expected { line: usize, expected: &'static str },
+    #[snafu(display("line {line}: bad $-escape (literal $ must be written as $$)"))]
+    BadEscape { line: usize },
+    #[snafu(display("line {line}: unexpected indentation"))]
+    UnexpectedIndentation { line: usize },
+    #[snafu(display("line {line}: unexpected NUL byte"))]
+    UnexpectedNul { line: usize },
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NinjaFile {
+    pub statements: Vec<Statement>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Statement {
+    Variable(Binding),
+    Pool(Pool),
+    Rule(Rule),
+    Build(Build),
+    Default(Vec<EvalString>),
+    Include(EvalString),
+    Subninja(EvalString),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Binding {
+    pub name: String,
+    pub value: EvalString,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pool {
+    pub name: String,
+    pub bindings: Vec<Binding>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    pub name: String,
+    pub bindings: Vec<Binding>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Build {
+    pub outputs: Vec<EvalString>,
+    pub implicit_outputs: Vec<EvalString>,
+    pub rule_name: String,
+    pub inputs: Vec<EvalString>,
+    pub implicit_dependencies: Vec<EvalString>,
+    pub order_only_dependencies: Vec<EvalString>,
+    pub validations: Vec<EvalString>,
+    pub bindings: Vec<Binding>,
+}
+
+/// Unevaluated string, like `cargo fmt -p $project`
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct EvalString(pub Vec<Piece>);
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Piece {
+    Literal(Vec<u8>),
+    Variable(String),
+}
+
+impl EvalString {
+    /// Return the content if there is no variable reference
+    #[must_use]
+    pub fn as_literal(&self) -> Option<&[u8]> {
+        match self.0.as_slice() {
+            [] => Some(b""),
+            [Piece::Literal(literal)] => Some(literal),
+            _ => None,
+        }
+    }
+
+    fn push_literal(&mut self, bytes: &[u8]) {
+        if let Some(Piece::Literal(literal)) = self.0.last_mut() {
+            literal.extend_from_slice(bytes);
+        } else {
+            self.0.push(Piece::Literal(bytes.to_vec()));
+        }
+    }
+}
+
+impl Display for Statement {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Variable(binding) => write_binding(f, "", binding),
+            Self::Pool(Pool { name, bindings }) => {
+                writeln!(f, "pool {name}")?;
+                bindings.iter().try_for_each(|binding| write_binding(f, "  ", binding))
+            }
+            Self::Rule(Rule { name, bindings }) => {
+                writeln!(f, "rule {name}")?;
+                bindings.iter().try_for_each(|binding| write_binding(f, "  ", binding))
+            }
+            Self::Build(build) => {
+                write!(f, "build")?;
+                write_paths(f, "", &build.outputs)?;
+                write_paths(f, " |", &build.implicit_outputs)?;
+                write!(f, ": {}", build.rule_name)?;
+                write_paths(f, "", &build.inputs)?;
+                write_paths(f, " |", &build.implicit_dependencies)?;
+                write_paths(f, " ||", &build.order_only_dependencies)?;
+                write_paths(f, " |@", &build.validations)?;
+                writeln!(f)?;
+                build.bindings.iter().try_for_each(|binding| write_binding(f, "  ", binding))
+            }
+            Self::Default(targets) => {
+                write!(f, "default")?;
+                write_paths(f, "", targets)?;
+                writeln!(f)
+            }
+            Self::Include(path) => {
+                writeln!(f, "include {path}")
+            }
+            Self::Subninja(path) => {
+                writeln!(f, "subninja {path}")
+            }
+        }
+    }
+}
+
+/// Write the string like a path, with `$`, spaces and `:` escaped
+impl Display for EvalString {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write_eval_string(f, self, "$ :")
+    }
+}
+
+fn write_binding(formatter: &mut Formatter<'_>, indent: &str, binding: &Binding) -> fmt::Result {
+    write!(formatter, "{indent}{} = ", binding.name)?;
+    write_eval_string(formatter, &binding.value, "$")?;
+    writeln!(formatter)
+}
+
+fn write_paths(
+    formatter: &mut Formatter<'_>,
+    separator: &str,
+    paths: &[EvalString],
+) -> fmt::Result {
+    if !paths.is_empty() {
+        write!(formatter, "{separator}")?;
+    }
+    paths.iter().try_for_each(|path| write!(formatter, " {path}"))
+}
+
+/// Write an `EvalString` with a `$` before each character of `escaped_characters`
+///
+/// Non-UTF-8 bytes are replaced with `U+FFFD`.
+fn write_eval_string(
+    formatter: &mut Formatter<'_>,
+    eval_string: &EvalString,
+    escaped_characters: &str,
+) -> fmt::Result {
+    let mut pieces = eval_string.0.iter().peekable();
+    while let Some(piece) = pieces.next() {
+        match piece {
+            Piece::Literal(literal) => {
+                for character in String::from_utf8_lossy(literal).chars() {
+                    if escaped_characters.contains(character) {
+                        write!(formatter, "$")?;
+                    }
+                    write!(formatter, "{character}")?;
+                }
+            }
+            Piece::Variable(name) => {
+                // `$name` would be ambiguous if followed by a byte of a variable name.
+                let is_ambiguous = name.contains('.')
+                    || matches!(
+                        pieces.peek(),
+                        Some(Piece::Literal(literal))
+                            if literal.first().is_some_and(|&byte| is_identifier_byte(byte))
+                    );
+                if is_ambiguous {
+                    write!(formatter, "${{{name}}}")?;
+                } else {
+                    write!(formatter, "${name}")?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn parse(input: &[u8]) -> Result<NinjaFile, Error> {
+    // Like Ninja, which reads a NUL-terminated buffer, reject NUL bytes.
+    if let Some(position) = input.iter().position(|&byte| byte == 0) {
+        let lexer = Lexer { input, position, token_start: 0 };
+        UnexpectedNulSnafu { line: lexer.line() }.fail()?;
+    }
+    let mut parser = Parser { lexer: Lexer { input, position: 0, token_start: 0 } };
+    parser.parse_file()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Token {
+    Build,
+    Default,
+    Include,
+    Pool,
+    Rule,
+    Subninja,
+    Identifier,
+    Equals,
+    Colon,
+    Pipe,
+    Pipe2,
+    PipeAt,
+    Indentation,
+    Newline,
+    Eof,
+    Unknown,
+}
+
+struct Lexer<'a> {
+    input: &'a [u8],
+    position: usize,
+    /// Position of the last token read by `read_token`, after the comments and the indentation
+    token_start: usize,
+}
+
+impl Lexer<'_> {
+    fn line(&self) -> usize {
+        self.line_at(self.position)
+    }
+
+    /// Count the lines up to `position`. This scans the input from the start, so it is only called
+    /// to report an error.
+    fn line_at(&self, position: usize) -> usize {
+        self.input[..position].split(|&byte| byte == b'\n').count()
+    }
+
+    fn peek_byte(&self, offset: usize) -> Option<u8> {
+        self.input.get(self.position + offset).copied()
+    }
+
+    fn count_spaces(&self) -> usize {
+        self.input[self.position..].iter().take_while(|&&byte| byte == b' ').count()
+    }
+
+    fn count_identifier_bytes(&self, is_simple: bool) -> usize {
+        self.input[self.position..]
+            .iter()
+            .take_while(|&&byte| is_identifier_byte(byte) && !(is_simple && byte == b'.'))
+            .count()
+    }
+
+    /// Read the next token, like `Lexer::ReadToken` in Ninja
+    fn read_token(&mut self) -> Token {
+        let token = loop {
+            let spaces = self.count_spaces();
+            let start = self.position + spaces;
+            self.token_start = start;
+            match self.input.get(start) {
+                Some(b'#') => {
+                    self.position = match self.input[start..].iter().position(|&byte| byte == b'\n')
+                    {
+                        Some(offset) => start + offset + 1,
+                        None => self.input.len(),
+                    };
+                }
+                Some(b'\n') => {
+                    self.position = start + 1;
+                    break Token::Newline;
+                }
+                Some(b'\r') if self.input.get(start + 1) == Some(&b'\n') => {
+                    self.position = start + 2;
+                    break Token::Newline;
+                }
+                _ if spaces > 0 => {
+                    self.position = start;
+                    break Token::Indentation;
+                }
+                None => break Token::Eof,
+                Some(_) => break self.read_word_or_symbol(),
+            }
+        };
+        if !matches!(token, Token::Newline | Token::Eof) {
+            self.eat_whitespace();
+        }
+        token
+    }
+
+    fn read_word_or_symbol(&mut self) -> Token {
+        let size = self.count_identifier_bytes(false);
+        if size > 0 {
+            let word = &self.input[self.position..self.position + size];
+            self.position += size;
+            return match word {
+                b"build" => Token::Build,
+                b"default" => Token::Default,
+                b"include" => Token::Include,
+                b"pool" => Token::Pool,
+                b"rule" => Token::Rule,
+                b"subninja" => Token::Subninja,
+                _ => Token::Identifier,
+            };
+        }
+        let (token, size) = match (self.peek_byte(0), self.peek_byte(1)) {
+            (Some(b'='), _) => (Token::Equals, 1),
+            (Some(b':'), _) => (Token::Colon, 1),
+            (Some(b'|'), Some(b'|')) => (Token::Pipe2, 2),
+            (Some(b'|'), Some(b'@')) => (Token::PipeAt, 2),
+            (Some(b'|'), _) => (Token::Pipe, 1),
+            _ => (Token::Unknown, 1),
+        };
+        self.position += size;
+        token
+    }
+
+    fn peek_token(&mut self, expected: Token) -> bool {
+        let position = self.position;
+        if self.read_token() == expected {
+            true
+        } else {
+            self.position = position;
+            false
+        }
+    }
+
+    fn expect_token(&mut self, expected: Token, description: &'static str) -> Result<(), Error> {
+        let start = self.position;
+        ensure!(
+            self.read_token() == expected,
+            ExpectedSnafu { line: self.line_at(start), expected: description }
+        );
+        Ok(())
+    }
+
+    /// Skip the spaces and the line continuations
+    fn eat_whitespace(&mut self) {
+        loop {
+            match (self.peek_byte(0), self.peek_byte(1), self.peek_byte(2)) {
+                (Some(b' '), _, _) => self.position += 1,
+                (Some(b'$'), Some(b'\n'), _) => self.position += 2,
+                (Some(b'$'), Some(b'\r'), Some(b'\n')) => self.position += 3,
+                _ => return,
+            }
+        }
+    }
+
+    fn read_identifier(&mut self, expected: &'static str) -> Result<String, Error> {
+        let size = self.count_identifier_bytes(false);
+        ensure!(size > 0, ExpectedSnafu { line: self.line(), expected });
+        let identifier = &self.input[self.position..self.position + size];
+        self.position += size;
+        self.eat_whitespace();
+        // Identifier bytes are ASCII.
+        Ok(String::from_utf8_lossy(identifier).into_owned())
+    }
+
+    fn read_path(&mut self) -> Result<EvalString, Error> {
+        let path = self.read_eval_string(true)?;
+        self.eat_whitespace();
+        Ok(path)
+    }
+
+    /// Read a value, including the end of the line
+    fn read_value(&mut self) -> Result<EvalString, Error> {
+        self.read_eval_string(false)
+    }
+
+    /// Read a string, like `Lexer::ReadEvalString` in Ninja
+    fn read_eval_string(&mut self, is_path: bool) -> Result<EvalString, Error> {
+        let mut eval_string = EvalString::default();
+        loop {
+            match (self.peek_byte(0), self.peek_byte(1)) {
+                (None, _) => {
+                    ExpectedSnafu { line: self.line(), expected: "a newline" }.fail()?;
+                }
+                (Some(b'\r'), Some(b'\n')) => {
+                    if !is_path {
+                        self.position += 2;
+                    }
+                    return Ok(eval_string);
+                }
+                (Some(b'\n'), _) => {
+                    if !is_path {
+                        self.position += 1;
+                    }
+                    return Ok(eval_string);
+                }
+                (Some(byte @ (b' ' | b':' | b'|')), _) => {
+                    if is_path {
+                        return Ok(eval_string);
+                    }
+                    eval_string.push_literal(&[byte]);
+                    self.position += 1;
+                }
+                (Some(b'$'), next) => {
+                    self.position += 1;
+                    self.read_escape(next, &mut eval_string)?;
+                }
+                (Some(byte), _) => {
+                    eval_string.push_literal(&[byte]);
+                    self.position += 1;
+                }
+            }
+        }
+    }
+
+    /// Read what follows a `$`
+    fn read_escape(&mut self, next: Option<u8>, eval_string: &mut EvalString) -> Result<(), Error> {
+        match next {
+            Some(byte @ (b'$' | b' ' | b':')) => {
+                eval_string.push_literal(&[byte]);
+                self.position += 1;
+            }
+            Some(b'\n') => {
+                self.position += 1;
+                self.position += self.count_spaces();
+            }
+            Some(b'\r') if self.peek_byte(1) == Some(b'\n') => {
+                self.position += 2;
+                self.position += self.count_spaces();
+            }
+            Some(b'{') => {
+                self.position += 1;
+                let size = self.count_identifier_bytes(false);
+                ensure!(
+                    size > 0 && self.peek_byte(size) == Some(b'}'),
+                    BadEscapeSnafu { line: self.line() }
+                );
+                let name = &self.input[self.position..self.position + size];
+                eval_string.0.push(Piece::Variable(String::from_utf8_lossy(name).into_owned()));
+                self.position += size + 1;
+            }
+            _ => {
+                let size = self.count_identifier_bytes(true);
+                ensure!(size > 0, BadEscapeSnafu { line: self.line() });
+                let name = &self.input[self.position..self.position + size];
+                eval_string.0.push(Piece::Variable(String::from_utf8_lossy(name).into_owned()));
+                self.position += size;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Byte of `[a-zA-Z0-9_.-]`, which is the set of the bytes of a variable name in Ninja
+pub(crate) const fn is_identifier_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || matches!(byte, b'_' | b'.' | b'-')
+}
+
+struct Parser<'a> {
+    lexer: Lexer<'a>,
+}
+
+impl Parser<'_> {
+    fn parse_file(&mut self) -> Result<NinjaFile, Error> {
+        let mut statements = Vec::new();
+        loop {
+            let token = self.lexer.read_token();
+            let statement = match token {
+                Token::Pool => Statement::Pool(self.parse_pool()?),
+                Token::Rule => Statement::Rule(self.parse_rule()?),
+                Token::Build => Statement::Build(self.parse_build()?),
+                Token::Default => Statement::Default(self.parse_default()?),
+                Token::Include => Statement::Include(self.parse_inclusion()?),
+                Token::Subninja => Statement::Subninja(self.parse_inclusion()?),
+                Token::Identifier => {
+                    self.lexer.position = self.lexer.token_start;
+                    Statement::Variable(self.parse_binding()?)
+                }
+                Token::Newline => continue,
+                Token::Eof => return Ok(NinjaFile { statements }),
+                Token::Indentation => {
+                    UnexpectedIndentationSnafu { line: self.lexer.line_at(self.lexer.token_start) }
+                        .fail()?
+                }
+                _ => ExpectedSnafu {
+                    line: self.lexer.line_at(self.lexer.token_start),
+                    expected: "a statement",
+                }
+                .fail()?,
+            };
+            statements.push(statement);
+        }
+    }
+
+    fn parse_binding(&mut self) -> Result<Binding, Error> {
+        let name = self.lexer.read_identifier("a variable name")?;
+        self.lexer.expect_token(Token::Equals, "`=`")?;
+        let value = self.lexer.read_value()?;
+        Ok(Binding { name, value })
+    }
+
+    fn parse_indented_bindings(&mut self) -> Result<Vec<Binding>, Error> {
+        let mut bindings = Vec::new();
+        while self.lexer.peek_token(Token::Indentation) {
+            bindings.push(self.parse_binding()?);
+        }
+        Ok(bindings)
+    }
+
+    fn parse_pool(&mut self) -> Result<Pool, Error> {
+        let name = self.lexer.read_identifier("a pool name")?;
+        self.lexer.expect_token(Token::Newline, "a newline")?;
+        let bindings = self.parse_indented_bindings()?;
+        Ok(Pool { name, bindings })
+    }
+
+    fn parse_rule(&mut self) -> Result<Rule, Error> {
+        let name = self.lexer.read_identifier("a rule name")?;
+        self.lexer.expect_token(Token::Newline, "a newline")?;
+        let bindings = self.parse_indented_bindings()?;
+        Ok(Rule { name, bindings })
+    }
+
+    fn parse_build(&mut self) -> Result<Build, Error> {
+        let start = self.lexer.position;
+        let outputs = self.parse_paths()?;
+        let implicit_outputs = self.parse_paths_after(Token::Pipe)?;
+        ensure!(
+            !outputs.is_empty() || !implicit_outputs.is_empty(),
+            ExpectedSnafu { line: self.lexer.line_at(start), expected: "a path" }
+        );
+        self.lexer.expect_token(Token::Colon, "`:`")?;
+        let rule_name = self.lexer.read_identifier("a rule name")?;
+        let inputs = self.parse_paths()?;
+        let implicit_dependencies = self.parse_paths_after(Token::Pipe)?;
+        let order_only_dependencies = self.parse_paths_after(Token::Pipe2)?;
+        let validations = self.parse_paths_after(Token::PipeAt)?;
+        self.lexer.expect_token(Token::Newline, "a newline")?;
+        let bindings = self.parse_indented_bindings()?;
+        Ok(Build {
+            outputs,
+            implicit_outputs,
+            rule_name,
+            inputs,
+            implicit_dependencies,
+            order_only_dependencies,
+            validations,
+            bindings,
+        })
+    }
+
+    fn parse_default(&mut self) -> Result<Vec<EvalString>, Error> {
+        let start = self.lexer.position;
+        let targets = self.parse_paths()?;
+        ensure!(
+            !targets.is_empty(),
+            ExpectedSnafu { line: self.lexer.line_at(start), expected: "a target name" }
+        );
+        self.lexer.expect_token(Token::Newline, "a newline")?;
+        Ok(targets)
+    }
+
+    fn parse_inclusion(&mut self) -> Result<EvalString, Error> {
+        let start = self.lexer.position;
+        let path = self.lexer.read_path()?;
+        ensure!(
+            !path.0.is_empty(),
+            ExpectedSnafu { line: self.lexer.line_at(start), expected: "a path" }
+        );
+        self.lexer.expect_token(Token::Newline, "a newline")?;
+        Ok(path)
+    }
+
+    fn parse_paths(&mut self) -> Result<Vec<EvalString>, Error> {
+        let mut paths = Vec::new();
+        loop {
+            let path = self.lexer.read_path()?;
+            if path.0.is_empty() {
+                return Ok(paths);
+            }
+            paths.push(path);
+        }
+    }
+
+    fn parse_paths_after(&mut self, separator: Token) -> Result<Vec<EvalString>, Error> {
+        if self.lexer.peek_token(separator) { self.parse_paths() } else { Ok(Vec::new()) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use proptest::collection::vec;
+    use proptest::option;
+    use proptest::prelude::*;
+
+    use crate::ninja_writer::{
+        self, AfterBuildPool, AfterDyndep, AfterValidation, AfterVariableAndValue, Config, Expr,
+        NinjaWriter,
+    };
+
+    #[test]
+    fn parse_escapes_and_line_continuations() {
+        let input =
+            b"build a$ b$:c$$d | $\n    e: cc_${x}y f$\r\n  g\n  flags = $ -O2 $x$\n    -g\n";
+        let expected = NinjaFile {
+            statements: vec![Statement::Build(Build {
+                outputs: vec![literal(b"a b:c$d")],
+                implicit_outputs: vec![literal(b"e")],
+                rule_name: "cc_".into(),
+                inputs: vec![
+                    EvalString(vec![Piece::Variable("x".into()), Piece::Literal(b"y".into())]),
+                    literal(b"fg"),
+                ],
+                bindings: vec![Binding {
+                    name: "flags".into(),
+                    value: EvalString(vec![
+                        Piece::Literal(b" -O2 ".into()),
+                        Piece::Variable("x".into()),
+                        Piece::Literal(b"-g".into()),
+                    ]),
+                }],
+                ..Build::default()
+            })],
+        };
+        assert_eq!(parse(input).unwrap(), expected);
+    }
+
+    #[test]
+    fn parse_comments_and_empty_lines() {
+        let input =
+            b"# comment\n\nx = 1 # not a comment\n  # indented comment\ndefault y\n# z\nz = 2\n";
+        let expected = NinjaFile {
+            statements: vec![
+                Statement::Variable(Binding {
+                    name: "x".into(),
+                    value: literal(b"1 # not a comment"),
+                }),
+                Statement::Default(vec![literal(b"y")]),
+                Statement::Variable(Binding { name: "z".into(), value: literal(b"2") }),
+            ],
+        };
+        assert_eq!(parse(input).unwrap(), expected);
+    }
+
+    #[test]
+    fn reject_invalid_files() {
+        for input in [
+            &b"build a$|b: phony\n"[..],
+            b"build a: phony $#\n",
+            b"  x = 1\n",
+            b"x = 1",
+            b"x = \0\n",
+            b"build : phony\n",
+            b"default\n",
+        ] {
+            assert!(parse(input).is_err(), "{:?}", String::from_utf8_lossy(input));
+        }
+    }
+
+    #[test]
+    fn report_the_line_of_an_error() {
+        for (input, message) in [
+            (&b"x = 1\n\nbuild : phony\n"[..], "line 3: expected a path"),
+            (b"x = 1\n  y = 2\n", "line 2: unexpected indentation"),
+            (b"build a: $\n  phony\ny = $!\n", "line 3: bad $-escape"),
+        ] {
+            let error = parse(input).unwrap_err().to_string();
+            assert!(error.starts_with(message), "{error}");
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn parse_what_the_writer_writes(
+            statements in vec(statement_strategy(), 0..12),
+            width in 40_usize..=120,
+        ) {
+            let mut output = Vec::new();
+            let mut ninja_writer = NinjaWriter::new(Config::with_width(width), &mut output);
+            for statement in &statements {
+                write_statement(&mut ninja_writer, statement).unwrap();
+            }
+            let expected = NinjaFile { statements: statements.iter().filter_map(to_statement).collect() };
+            prop_assert_eq!(parse(&output).unwrap(), expected);
+            // A path is never cut, so it may not fit in a line, even after a line continuation.
+            let long_paths: Vec<Vec<u8>> = statements
+                .iter()
+                .flat_map(statement_paths)
+                .map(escape_path)
+                .filter(|path| path.len() + 7 > width)
+                .collect();
+            for line in output.split(|&byte| byte == b'\n') {
+                prop_assert!(
+                    line.len() <= width
+                        || long_paths.iter().any(|path| line.windows(path.len()).any(|window| window == path)),
+                    "{:?}",
+                    String::from_utf8_lossy(line)
+                );
+            }
+        }
+
+        #[test]
+        fn refuse_to_write_unrepresentable_paths(
+            prefix in path_strategy(),
+            forbidden in prop::sample::select(&b"\0|\n\r"[..]),
+            suffix in path_strategy(),
+        ) {
+            let path = [prefix.as_slice(), &[forbidden], suffix.as_slice()].concat();
+            let mut output = Vec::new();
+            let mut ninja_writer = NinjaWriter::new(Config::with_width(80), &mut output);
+            prop_assert!(ninja_writer.include(&path).is_err());
+            prop_assert!(ninja_writer.subninja(b"").is_err());
+            prop_assert!(ninja_writer.variable("x", &path).is_err() == (forbidden != b'|'));
+        }
+    }
+
+    /// Statement to write with `NinjaWriter`
+    #[derive(Debug, Clone)]
+    enum TestStatement {
+        Variable { name: String, value: Vec<u8> },
+        Pool { name: String, depth: usize },
+        Rule { name: String, command: Vec<Piece>, description: Option<Vec<Piece>>, restat: bool },
+        Build(TestBuild),
+        Default(Vec<Vec<u8>>),
+        Include(Vec<u8>),
+        Subninja(Vec<u8>),
+        Comment(String),
+        BlankLine,
+    }
+
+    #[derive(Debug, Clone)]
+    struct TestBuild {
+        outputs: Vec<Vec<u8>>,
+        implicit_outputs: Vec<Vec<u8>>,
+        rule_name: String,
+        inputs: Vec<Vec<u8>>,
+        implicit_dependencies: Vec<Vec<u8>>,
+        order_only_dependencies: Vec<Vec<u8>>,
+        validations: Vec<Vec<u8>>,
+        dyndep: Option<Vec<u8>>,
+        pool: Option<String>,
+        variables: Vec<(String, Vec<u8>)>,
+    }
+
+    fn statement_strategy() -> impl Strategy<Value = TestStatement> {
+        prop_oneof![
+            (name_strategy(), vec(value_byte_strategy(), 0..60))
+                .prop_map(|(name, value)| TestStatement::Variable { name, value }),
+            (name_strategy(), 0_usize..100)
+                .prop_map(|(name, depth)| TestStatement::Pool { name, depth }),
+            (
+                name_strategy(),
+                vec(piece_strategy(), 1..=12),
+                option::of(vec(piece_strategy(), 1..=6)),
+                any::<bool>(),
+            )
+                .prop_map(|(name, command, description, restat)| {
+                    TestStatement::Rule { name, command, description, restat }
+                }),
+            build_strategy().prop_map(TestStatement::Build),
+            vec(path_strategy(), 1..5).prop_map(TestStatement::Default),
+            path_strategy().prop_map(TestStatement::Include),
+            path_strategy().prop_map(TestStatement::Subninja),
+            "([a-z#$|:]{1,10}[ \n]){0,20}".prop_map(TestStatement::Comment),
+            Just(TestStatement::BlankLine),
+        ]
+    }
+
+    fn build_strategy() -> impl Strategy<Value = TestBuild> {
+        (
+            (
+                vec(path_strategy(), 1..4),
+                vec(path_strategy(), 0..3),
+                name_strategy(),
+                vec(path_strategy(), 0..4),
+                vec(path_strategy(), 0..3),
+            ),
+            (
+                vec(path_strategy(), 0..3),
+                vec(path_strategy(), 0..3),
+                option::of(path_strategy()),
+                option::of(name_strategy()),
+                vec((name_strategy(), vec(value_byte_strategy(), 0..60)), 0..3),
+            ),
+        )
+            .prop_map(
+                |(
+                    (outputs, implicit_outputs, rule_name, inputs, implicit_dependencies),
+                    (order_only_dependencies, validations, dyndep, pool, variables),
+                )| TestBuild {
+                    outputs,
+                    implicit_outputs,
+                    rule_name,
+                    inputs,
+                    implicit_dependencies,
+                    order_only_dependencies,
+                    validations,
+                    dyndep,
+                    pool,
+                    variables,
+                },
+            )
+    }
+
+    /// Name of a variable, a rule or a pool, which is not a keyword
+    fn name_strategy() -> impl Strategy<Value = String> {
+        "[a-z_][a-z0-9_]{0,9}".prop_filter("keyword", |name| {
+            !["build", "default", "include", "pool", "rule", "subninja"].contains(&name.as_str())
+        })
+    }
+
+    /// Path with a bias towards the special bytes, sometimes longer than a line
+    ///
+    /// `b'|'`, `b'\n'`, `b'\r'` and `b'\0'` cannot be written in a path, so the writer rejects them:
+    /// see `refuse_to_write_unrepresentable_paths`.
+    fn path_strategy() -> impl Strategy<Value = Vec<u8>> {
+        prop_oneof![
+            4 => vec(path_byte_strategy(), 1..=15),
+            1 => vec(path_byte_strategy(), 30..=150),
+        ]
+    }
+
+    /// Any byte which can be in a path, with a bias towards the special bytes
+    fn path_byte_strategy() -> impl Strategy<Value = u8> {
+        prop_oneof![
+            prop::sample::select(&b" $:#=ab/"[..]),
+            any::<u8>().prop_filter("unrepresentable", |byte| !b"\0|\n\r".contains(byte)),
+        ]
+    }
+
+    /// Any byte which can be in a variable value, with a bias towards the special bytes
+    fn value_byte_strategy() -> impl Strategy<Value = u8> {
+        prop_oneof![
+            prop::sample::select(&b" $:#|=a"[..]),
+            any::<u8>().prop_filter("unrepresentable", |byte| !b"\0\n\r".contains(byte)),
+        ]
+    }
+
+    /// Piece of an expression, with a bias towards the references
+    fn piece_strategy() -> impl Strategy<Value = Piece> {
+        prop_oneof![
+            vec(value_byte_strategy(), 0..12).prop_map(Piece::Literal),
+            prop::sample::select(&["in", "out", "project", "out.d"][..])
+                .prop_map(|name| Piece::Variable(name.into())),
+        ]
+    }
+
+    fn statement_paths(statement: &TestStatement) -> Vec<&[u8]> {
+        match statement {
+            TestStatement::Build(build) => build
+                .outputs
+                .iter()
+                .chain(&build.implicit_outputs)
+                .chain(&build.inputs)
+                .chain(&build.implicit_dependencies)
+                .chain(&build.order_only_dependencies)
+                .chain(&build.validations)
+                .chain(&build.dyndep)
+                .map(Vec::as_slice)
+                .collect(),
+            TestStatement::Default(targets) => targets.iter().map(Vec::as_slice).collect(),
+            TestStatement::Include(path) | TestStatement::Subninja(path) => vec![path],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Escape a path like the writer, with `b'$'` before the bytes in `b"$ :"`
+    fn escape_path(path: &[u8]) -> Vec<u8> {
+        let mut escaped = Vec::new();
+        for &byte in path {
+            if matches!(byte, b'$' | b' ' | b':') {
+                escaped.push(b'$');
+            }
+            escaped.push(byte);
+        }
+        escaped
+    }
+
+    fn write_statement(
+        ninja_writer: &mut NinjaWriter<&mut Vec<u8>>,
+        statement: &TestStatement,
+    ) -> Result<(), ninja_writer::Error> {
+        match statement {
+            TestStatement::Variable { name, value } => ninja_writer.variable(name, value),
+            TestStatement::Pool { name, depth } => ninja_writer.pool(name)?.depth(*depth)?.end(),
+            TestStatement::Rule { name, command, description, restat } => {
+                let step = ninja_writer.rule(name)?.command(to_expr(command))?;
+                match (description, restat) {
+                    (None, false) => step.end(),
+                    (None, true) => step.restat()?.end(),
+                    (Some(description), false) => step.description(to_expr(description))?.end(),
+                    (Some(description), true) => {
+                        step.description(to_expr(description))?.restat()?.end()
+                    }
+                }
+            }
+            TestStatement::Build(build) => write_build(ninja_writer, build),
+            TestStatement::Default(targets) => {
+                ninja_writer.default(&targets[0])?.targets(&targets[1..])?.end()
+            }
+            TestStatement::Include(path) => ninja_writer.include(path),
+            TestStatement::Subninja(path) => ninja_writer.subninja(path),
+            TestStatement::Comment(text) => ninja_writer.comment(text),
+            TestStatement::BlankLine => ninja_writer.blank_line(),
+        }
+    }
+
+    fn write_build(
+        ninja_writer: &mut NinjaWriter<&mut Vec<u8>>,
+        build: &TestBuild,
+    ) -> Result<(), ninja_writer::Error> {
+        let step = ninja_writer
+            .build()?
+            .output(&build.outputs[0])?
+            .outputs(&build.outputs[1..])?
+            .implicit_outputs(&build.implicit_outputs)?
+            .rule(&build.rule_name)?
+            .inputs(&build.inputs)?
+            .implicit_dependencies(&build.implicit_dependencies)?
+            .order_only_dependencies(&build.order_only_dependencies)?
+            .validations(&build.validations)?;
+        let variables = &build.variables;
+        match (&build.dyndep, &build.pool) {
+            (None, None) => write_build_variables(step, variables),
+            (None, Some(pool)) => write_build_variables(step.pool(pool)?, variables),
+            (Some(dyndep), None) => write_build_variables(step.dyndep(dyndep)?, variables),
+            (Some(dyndep), Some(pool)) => {
+                write_build_variables(step.dyndep(dyndep)?.pool(pool)?, variables)
+            }
+        }
+    }
+
+    /// Step of a build definition which can be followed by variables
+    trait BeforeVariables<'a, W: Write>: Sized {
+        fn first_variable(
+            self,
+            variable: &str,
+            value: &[u8],
+        ) -> Result<AfterVariableAndValue<'a, W>, ninja_writer::Error>;
+        fn end_without_variables(self) -> Result<(), ninja_writer::Error>;
+    }
+
+    macro_rules! impl_before_variables {
+        ($($state:ident),*) => {$(
+            impl<'a, W: Write> BeforeVariables<'a, W> for $state<'a, W> {
+                fn first_variable(
+                    self,
+                    variable: &str,
+                    value: &[u8],
+                ) -> Result<AfterVariableAndValue<'a, W>, ninja_writer::Error> {
+                    self.variable_and_value(variable, value)
+                }
+                fn end_without_variables(self) -> Result<(), ninja_writer::Error> {
+                    self.end()
+                }
+            }
+        )*};
+    }
+
+    impl_before_variables!(AfterValidation, AfterDyndep, AfterBuildPool);
+
+    fn write_build_variables<'a, W: Write + 'a>(
+        step: impl BeforeVariables<'a, W>,
+        variables: &[(String, Vec<u8>)],
+    ) -> Result<(), ninja_writer::Error> {
+        let Some(((name, value), rest)) = variables.split_first() else {
+            return step.end_without_variables();
+        };
+        let mut step = step.first_variable(name, value)?;
+        for (name, value) in rest {
+            step = step.variable_and_value(name, value)?;
+        }
+        step.end()
+    }
+
+    fn to_expr(pieces: &[Piece]) -> Expr {
+        pieces.iter().fold(Expr::new(), |expr, piece| match piece {
+            Piece::Literal(literal) => expr.text(literal),
+            Piece::Variable(name) => expr.variable(name),
+        })
+    }
+
+    /// Expected result of the parsing of what `write_statement` writes, which ignores the comments
+    /// and the blank lines
+    fn to_statement(statement: &TestStatement) -> Option<Statement> {
+        Some(match statement {
+            TestStatement::Variable { name, value } => {
+                Statement::Variable(Binding { name: name.clone(), value: literal(value) })
+            }
+            TestStatement::Pool { name, depth } => Statement::Pool(Pool {
+                name: name.clone(),
+                bindings: vec![binding("depth", depth.to_string().as_bytes())],
+            }),
+            TestStatement::Rule { name, command, description, restat } => {
+                let mut bindings =
+                    vec![Binding { name: "command".into(), value: to_eval_string(command) }];
+                if let Some(description) = description {
+                    bindings.push(Binding {
+                        name: "description".into(),
+                        value: to_eval_string(description),
+                    });
+                }
+                if *restat {
+                    bindings.push(binding("restat", b"1"));
+                }
+                Statement::Rule(Rule { name: name.clone(), bindings })
+            }
+            TestStatement::Build(build) => {
+                let mut bindings = Vec::new();
+                if let Some(dyndep) = &build.dyndep {
+                    bindings.push(binding("dyndep", dyndep));
+                }
+                if let Some(pool) = &build.pool {
+                    bindings.push(binding("pool", pool.as_bytes()));
+                }
+                bindings.extend(build.variables.iter().map(|(name, value)| binding(name, value)));
+                Statement::Build(Build {
+                    outputs: literals(&build.outputs),
+                    implicit_outputs: literals(&build.implicit_outputs),
+                    rule_name: build.rule_name.clone(),
+                    inputs: literals(&build.inputs),
+                    implicit_dependencies: literals(&build.implicit_dependencies),
+                    order_only_dependencies: literals(&build.order_only_dependencies),
+                    validations: literals(&build.validations),
+                    bindings,
+                })
+            }
+            TestStatement::Default(targets) => Statement::Default(literals(targets)),
+            TestStatement::Include(path) => Statement::Include(literal(path)),
+            TestStatement::Subninja(path) => Statement::Subninja(literal(path)),
+            TestStatement::Comment(_) | TestStatement::BlankLine => return None,
+        })
+    }
+
+    fn to_eval_string(pieces: &[Piece]) -> EvalString {
+        let mut eval_string = EvalString::default();
+        for piece in pieces {
+            match piece {
+                Piece::Literal(literal) if literal.is_empty() => {}
+                Piece::Literal(literal) => eval_string.push_literal(literal),
+                Piece::Variable(_) => eval_string.0.push(piece.clone()),
+            }
+        }
+        eval_string
+    }
+
+    fn binding(name: &str, value: &[u8]) -> Binding {
+        Binding { name: name.into(), value: literal(value) }
+    }
+
+    fn literal(bytes: &[u8]) -> EvalString {
+        to_eval_string(&[Piece::Literal(bytes.to_vec())])
+    }
+
+    fn literals(paths: &[Vec<u8>]) -> Vec<EvalString> {
+        paths.iter().map(|path| literal(path)).collect()
+    }
+}
@@ -1,13 +1,29 @@
 //! Write a Ninja build file with the typestate pattern
 //!
-//! A lot of features are missing. Currently, only the useful ones to build `ninja_bootstrap` are
-//! implemented.
+//! A few features of the Ninja language are missing, but the writer covers the rule, build, pool,
+//! variable, default, include and subninja statements, as well as comments and blank lines.
+//!
+//! The paths can be given as bytes, strings or platform paths: see [`NinjaPath`]. A path cannot
+//! contain `|`, a newline or a NUL byte, because Ninja has no escape sequence for them, so such a
+//! path is rejected. The values of the variables are expressions: see [`Expr`].
+//!
+//! Optionally, the writer can also check the dependency graph, to report some mistakes before
+//! Ninja reads the file: see [`Validation`]. It can also record the build edges, to export the
+//! graph: see [`Graph`].
+//!
+//! [`Graph`]: crate::ninja_graph::Graph
 
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
 use std::error;
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use snafu::{OptionExt as _, ResultExt as _, Snafu, ensure};
 
-use snafu::{ResultExt as _, Snafu};
+use crate::ninja_graph::Edge;
 
 #[must_use]
 #[derive(Clone, Copy)]
@@ -39,17 +55,67 @@ enum InnerErrorOr<E: error::Error + 'static> {
 pub struct Error(InnerError);
 
 #[derive(Debug, Snafu)]
-enum InnerError {
+pub(crate) enum InnerError {
+    #[snafu(display("failed to write the variable {variable:?} with the value {value:?}"))]
+    Variable { source: io::Error, variable: String, value: String },
+    #[snafu(display(
+        "the value {value:?} of the variable {variable:?} contains `\\0`, `\\n` or `\\r`, so it \
+        cannot be written in a Ninja file"
+    ))]
+    UnrepresentableValue { variable: String, value: String },
+    #[snafu(display(
+        "the value of the variable {variable:?} refers to the variable {reference:?}, whose name \
+        is empty or contains a byte which is not ASCII alphanumeric, `_`, `-` or `.`"
+    ))]
+    InvalidReference { variable: String, reference: String },
+    #[snafu(display(
+        "the path {path:?} is empty or contains `\\0`, `|`, `\\n` or `\\r`, so it cannot be \
+        written in a Ninja file"
+    ))]
+    UnrepresentablePath { path: String },
+    #[snafu(display(
+        "the path {path:?} is not valid UTF-8, so it cannot be written in a Ninja file on this \
+        platform"
+    ))]
+    NonUtf8Path { path: String },
+    #[snafu(display("failed to write the beginning of a default statement"))]
+    Default { source: io::Error },
+    #[snafu(display("failed to write, in a default statement, the target {target:?}"))]
+    DefaultTarget { source: io::Error, target: String },
+    #[snafu(display("failed to write the end of a default statement"))]
+    DefaultEnd { source: io::Error },
+    #[snafu(display("failed to write the inclusion of {path:?}"))]
+    Include { source: io::Error, path: String },
+    #[snafu(display("failed to write the inclusion of {path:?} in a new scope"))]
+    Subninja { source: io::Error, path: String },
+    #[snafu(display(
+        "the comment {comment:?} contains `\\0`, so it cannot be written in a Ninja file"
+    ))]
+    UnrepresentableComment { comment: String },
+    #[snafu(display("failed to write the comment {comment:?}"))]
+    Comment { source: io::Error, comment: String },
+    #[snafu(display("failed to write a blank line"))]
+    BlankLine { source: io::Error },
+    #[snafu(display("failed to write the beginning of the declaration of the pool {pool_name:?}"))]
+    Pool { source: io::Error, pool_name: String },
+    #[snafu(display("failed to write, in a pool declaration, the depth {depth}"))]
+    Depth { source: io::Error, depth: usize },
+    #[snafu(display("failed to write the end of a pool declaration"))]
+    PoolEnd { source: io::Error },
     #[snafu(display("failed to write the beginning of the definition of the rule {rule_name:?}"))]
     Rule { source: io::Error, rule_name: String },
     #[snafu(display("failed to write, in a rule definition, the command {command:?}"))]
     Command { source: io::Error, command: String },
+    #[snafu(display(
+        "failed to write, in a rule definition, the variable {variable:?} with the value {value:?}"
+    ))]
+    RuleVariable { source: io::Error, variable: &'static str, value: String },
     #[snafu(display("failed to write the end of a rule definition"))]
     RuleEnd { source: io::Error },
-    #[snafu(display("failed to write the beginning of a build definition"))]
-    Beginning { source: io::Error },
     #[snafu(display("failed to write, in a build definition, the output {output:?}"))]
     Output { source: io::Error, output: String },
+    #[snafu(display("failed to write, in a build definition, the implicit output {output:?}"))]
+    ImplicitOutput { source: io::Error, output: String },
     #[snafu(display("failed to write, in a build definition, the rule {rule_name:?}"))]
     BuildRule { source: io::Error, rule_name: String },
     #[snafu(display("failed to write, in a build definition, the input {input:?}"))]
@@ -62,25 +128,731 @@ enum InnerError {
         "failed to write, in a build definition, the order-only dependency {dependency:?}"
     ))]
     OrderOnlyDependency { source: io::Error, dependency: String },
+    #[snafu(display("failed to write, in a build definition, the validation {validation:?}"))]
+    Validation { source: io::Error, validation: String },
+    #[snafu(display("failed to write, in a build definition, the dyndep file {dyndep:?}"))]
+    Dyndep { source: io::Error, dyndep: String },
     #[snafu(display(
         "failed to write, in a build definition, the variable {variable:?} with the value {value:?}"
     ))]
     VariableAndValue { source: io::Error, variable: String, value: String },
-    #[snafu(display("failed to write the end of a build definition"))]
+    #[snafu(display("failed to write a build definition"))]
     BuildEnd { source: io::Error },
+    #[snafu(display("failed to write the buffered definitions"), visibility(pub(crate)))]
+    Flush { source: io::Error },
+    #[snafu(display(
+        "the build definition uses the rule {rule_name:?}, which has not been declared"
+    ))]
+    UnknownRule { rule_name: String },
+    #[snafu(display("the output {output:?} is already written by another build definition"))]
+    DuplicateOutput { output: String },
+    #[snafu(display("the dependency cycle {cycle} would make Ninja fail"))]
+    DependencyCycle { cycle: String },
+}
+
+/// Name of the predeclared pool which gives the commands a direct access to the terminal:
+/// <https://ninja-build.org/manual.html#_the_literal_console_literal_pool>
+pub const CONSOLE_POOL: &str = "console";
+
+/// Value of the `deps` variable of a rule: <https://ninja-build.org/manual.html#ref_headers>
+#[derive(Clone, Copy)]
+pub enum Deps {
+    Gcc,
+    Msvc,
+}
+
+impl Deps {
+    const fn as_bytes(self) -> &'static [u8] {
+        match self {
+            Self::Gcc => b"gcc",
+            Self::Msvc => b"msvc",
+        }
+    }
+}
+
+/// Value of a variable, made of literal text and references to other variables:
+/// <https://ninja-build.org/manual.html#ref_lexer>
+///
+/// The text is escaped, so it is taken literally by Ninja. Bytes and strings convert to an
+/// expression without references. For example, the command `cat -- $in > $out` is:
+///
+/// ```
+/// # use ninja_bootstrap::ninja_writer::Expr;
+/// let command = Expr::new().text("cat -- ").variable("in").text(" > ").variable("out");
+/// ```
+#[must_use]
+#[derive(Clone, Debug, Default)]
+pub struct Expr(Vec<ExprPiece>);
+
+#[derive(Clone, Debug)]
+enum ExprPiece {
+    Text(Vec<u8>),
+    Variable(String),
+}
+
+impl Expr {
+    pub const fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    pub fn text(mut self, text: impl AsRef<[u8]>) -> Self {
+        let text = text.as_ref();
+        match self.0.last_mut() {
+            _ if text.is_empty() => {}
+            Some(ExprPiece::Text(previous_text)) => previous_text.extend_from_slice(text),
+            _ => self.0.push(ExprPiece::Text(text.to_vec())),
+        }
+        self
+    }
+
+    pub fn variable(mut self, name: impl Into<String>) -> Self {
+        self.0.push(ExprPiece::Variable(name.into()));
+        self
+    }
+
+    /// Escape the expression and cut it into the tokens between which a line can be continued.
+    fn escape(&self) -> EscapedExpr {
+        let mut escaped = EscapedExpr { bytes: Vec::new(), token_starts: Vec::new() };
+        for (index, piece) in self.0.iter().enumerate() {
+            match piece {
+                ExprPiece::Text(text) => {
+                    for &byte in text {
+                        escaped.token_starts.push(escaped.bytes.len());
+                        // Ninja skips the spaces after `=`.
+                        let is_leading_space =
+                            byte == b' ' && escaped.bytes.chunks(2).all(|chunk| chunk == b"$ ");
+                        if byte == b'$' || is_leading_space {
+                            escaped.bytes.push(b'$');
+                        }
+                        escaped.bytes.push(byte);
+                    }
+                }
+                ExprPiece::Variable(name) => {
+                    escaped.token_starts.push(escaped.bytes.len());
+                    let next_byte = match self.0.get(index + 1) {
+                        Some(ExprPiece::Text(text)) => text.first().copied(),
+                        _ => None,
+                    };
+                    if name.bytes().all(is_simple_name_byte)
+                        && !next_byte.is_some_and(is_simple_name_byte)
+                    {
+                        escaped.bytes.push(b'$');
+                        escaped.bytes.extend_from_slice(name.as_bytes());
+                    } else {
+                        escaped.bytes.extend_from_slice(b"${");
+                        escaped.bytes.extend_from_slice(name.as_bytes());
+                        escaped.bytes.push(b'}');
+                    }
+                }
+            }
+        }
+        escaped
+    }
+
+    /// Get the text as it is and the references as `${name}`, like in a [`Graph`].
+    ///
+    /// [`Graph`]: crate::ninja_graph::Graph
+    fn to_text(&self) -> String {
+        self.0
+            .iter()
+            .map(|piece| match piece {
+                ExprPiece::Text(text) => String::from_utf8_lossy(text).into_owned(),
+                ExprPiece::Variable(name) => format!("${{{name}}}"),
+            })
+            .collect()
+    }
+}
+
+impl<T: AsRef<[u8]>> From<T> for Expr {
+    fn from(text: T) -> Self {
+        Self::new().text(text)
+    }
+}
+
+/// Whether the byte can be in a variable name written without braces, like `$out`
+const fn is_simple_name_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || matches!(byte, b'_' | b'-')
+}
+
+/// Expression as written in the Ninja file
+struct EscapedExpr {
+    bytes: Vec<u8>,
+    /// Index in `bytes` of the beginning of each token
+    token_starts: Vec<usize>,
+}
+
+impl EscapedExpr {
+    fn token(&self, index: usize) -> &[u8] {
+        let end = self.token_starts.get(index + 1).copied().unwrap_or(self.bytes.len());
+        &self.bytes[self.token_starts[index]..end]
+    }
+
+    fn to_string_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.bytes)
+    }
+}
+
+/// Path which can be written in a Ninja file
+///
+/// Ninja reads the paths as bytes. On Unix, a platform path is written losslessly as its bytes. On
+/// the other platforms, its encoding is not the one of the file, so it is written only if it is
+/// valid UTF-8.
+pub trait NinjaPath {
+    /// Get the bytes to write, or `None` if the path cannot be written on this platform.
+    fn ninja_bytes(&self) -> Option<&[u8]>;
+
+    /// Get the path to display in an error message.
+    fn to_string_lossy(&self) -> Cow<'_, str>;
+}
+
+impl<T: NinjaPath + ?Sized> NinjaPath for &T {
+    fn ninja_bytes(&self) -> Option<&[u8]> {
+        (**self).ninja_bytes()
+    }
+
+    fn to_string_lossy(&self) -> Cow<'_, str> {
+        (**self).to_string_lossy()
+    }
+}
+
+impl NinjaPath for [u8] {
+    fn ninja_bytes(&self) -> Option<&[u8]> {
+        Some(self)
+    }
+
+    fn to_string_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self)
+    }
+}
+
+impl<const N: usize> NinjaPath for [u8; N] {
+    fn ninja_bytes(&self) -> Option<&[u8]> {
+        Some(self)
+    }
+
+    fn to_string_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self)
+    }
+}
+
+impl NinjaPath for Vec<u8> {
+    fn ninja_bytes(&self) -> Option<&[u8]> {
+        Some(self)
+    }
+
+    fn to_string_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self)
+    }
+}
+
+impl NinjaPath for str {
+    fn ninja_bytes(&self) -> Option<&[u8]> {
+        Some(self.as_bytes())
+    }
+
+    fn to_string_lossy(&self) -> Cow<'_, str> {
+        Cow::Borrowed(self)
+    }
+}
+
+impl NinjaPath for String {
+    fn ninja_bytes(&self) -> Option<&[u8]> {
+        Some(self.as_bytes())
+    }
+
+    fn to_string_lossy(&self) -> Cow<'_, str> {
+        Cow::Borrowed(self)
+    }
+}
+
+impl NinjaPath for Utf8Path {
+    fn ninja_bytes(&self) -> Option<&[u8]> {
+        Some(self.as_str().as_bytes())
+    }
+
+    fn to_string_lossy(&self) -> Cow<'_, str> {
+        Cow::Borrowed(self.as_str())
+    }
+}
+
+impl NinjaPath for Utf8PathBuf {
+    fn ninja_bytes(&self) -> Option<&[u8]> {
+        self.as_path().ninja_bytes()
+    }
+
+    fn to_string_lossy(&self) -> Cow<'_, str> {
+        Cow::Borrowed(self.as_str())
+    }
+}
+
+impl NinjaPath for OsStr {
+    #[cfg(unix)]
+    fn ninja_bytes(&self) -> Option<&[u8]> {
+        Some(std::os::unix::ffi::OsStrExt::as_bytes(self))
+    }
+
+    #[cfg(not(unix))]
+    fn ninja_bytes(&self) -> Option<&[u8]> {
+        self.to_str().map(str::as_bytes)
+    }
+
+    fn to_string_lossy(&self) -> Cow<'_, str> {
+        Self::to_string_lossy(self)
+    }
+}
+
+impl NinjaPath for OsString {
+    fn ninja_bytes(&self) -> Option<&[u8]> {
+        self.as_os_str().ninja_bytes()
+    }
+
+    fn to_string_lossy(&self) -> Cow<'_, str> {
+        self.as_os_str().to_string_lossy()
+    }
+}
+
+impl NinjaPath for Path {
+    fn ninja_bytes(&self) -> Option<&[u8]> {
+        self.as_os_str().ninja_bytes()
+    }
+
+    fn to_string_lossy(&self) -> Cow<'_, str> {
+        self.as_os_str().to_string_lossy()
+    }
+}
+
+impl NinjaPath for PathBuf {
+    fn ninja_bytes(&self) -> Option<&[u8]> {
+        self.as_os_str().ninja_bytes()
+    }
+
+    fn to_string_lossy(&self) -> Cow<'_, str> {
+        self.as_os_str().to_string_lossy()
+    }
 }
 
 pub struct NinjaWriter<W: Write> {
     config: Config,
     writer: W,
     current_line_size: usize,
+    current_section: Section,
+    /// Current build definition, written at its end, so that nothing of it is written if the
+    /// validation rejects it
+    build_buffer: Option<Vec<u8>>,
+    validation: Option<Validation>,
+    recorded_edges: Option<Vec<Edge>>,
+}
+
+/// Dependency graph of the definitions written so far
+///
+/// It reports before Ninja some mistakes which the typestate pattern cannot prevent: a build
+/// definition with a rule which has not been declared, an output written by several build
+/// definitions and a dependency cycle. Nothing of a rejected build definition is written.
+///
+/// The paths after `|@` are not checked: a validation is neither an output nor a dependency, so it
+/// can be written by no build definition and cannot create a cycle.
+///
+/// The graph can be moved from the writer of a file to the writer of an included file and back, so
+/// that it covers both. The rules are tracked without the scopes of the `subninja` statements and
+/// the paths are compared as written, without the canonicalization of Ninja.
+#[derive(Clone, Debug)]
+pub struct Validation {
+    rules: BTreeSet<Vec<u8>>,
+    /// Outputs of all the build definitions, including the phony targets
+    outputs: BTreeSet<Vec<u8>>,
+    edges: Vec<ValidationEdge>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct ValidationEdge {
+    outputs: Vec<Vec<u8>>,
+    /// Inputs, implicit dependencies and order-only dependencies, but not validations, which
+    /// cannot create a cycle
+    dependencies: Vec<Vec<u8>>,
+}
+
+/// State of a path during the depth-first search of a cycle
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Visit {
+    InProgress,
+    Done,
+}
+
+impl Validation {
+    /// Start with no declaration, except the predeclared `phony` rule
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            rules: BTreeSet::from([b"phony".to_vec()]),
+            outputs: BTreeSet::new(),
+            edges: Vec::new(),
+        }
+    }
+
+    /// Check that no path depends on itself, directly or through other build definitions.
+    ///
+    /// The graph is only complete when all the definitions have been written, so this check is
+    /// done at the end rather than after each build definition.
+    ///
+    /// # Panics
+    ///
+    /// Panics only on a bug of the depth-first search, whose paths in progress are all on its stack.
+    pub fn check_cycles(&self) -> Result<(), Error> {
+        let mut dependencies_by_output: BTreeMap<&[u8], Vec<&[u8]>> = BTreeMap::new();
+        for edge in &self.edges {
+            for output in &edge.outputs {
+                dependencies_by_output
+                    .entry(output)
+                    .or_default()
+                    .extend(edge.dependencies.iter().map(Vec::as_slice));
+            }
+        }
+        let mut visits: BTreeMap<&[u8], Visit> = BTreeMap::new();
+        for &start in dependencies_by_output.keys() {
+            if visits.contains_key(start) {
+                continue;
+            }
+            // Each element of the stack is a path and the index of its next dependency to visit.
+            let mut stack: Vec<(&[u8], usize)> = vec![(start, 0)];
+            visits.insert(start, Visit::InProgress);
+            while let Some(&mut (path, ref mut next_index)) = stack.last_mut() {
+                let dependencies = dependencies_by_output.get(path).map_or(&[][..], Vec::as_slice);
+                let Some(&dependency) = dependencies.get(*next_index) else {
+                    visits.insert(path, Visit::Done);
+                    stack.pop();
+                    continue;
+                };
+                *next_index += 1;
+                match visits.get(dependency) {
+                    Some(Visit::Done) => {}
+                    Some(Visit::InProgress) => {
+                        let cycle_start = stack
+                            .iter()
+                            .position(|&(stacked_path, _)| stacked_path == dependency)
+                            .expect("a path in progress is on the stack until it is done");
+                        let cycle: Vec<Cow<'_, str>> = stack[cycle_start..]
+                            .iter()
+                            .map(|&(stacked_path, _)| String::from_utf8_lossy(stacked_path))
+                            .chain([String::from_utf8_lossy(dependency)])
+                            .collect();
+                        return DependencyCycleSnafu { cycle: cycle.join(" -> ") }.fail()?;
+                    }
+                    None => {
+                        visits.insert(dependency, Visit::InProgress);
+                        stack.push((dependency, 0));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn add_rule(&mut self, rule_name: &[u8]) {
+        self.rules.insert(rule_name.to_vec());
+    }
+
+    fn check_rule(&self, rule_name: &[u8]) -> Result<(), Error> {
+        ensure!(
+            self.rules.contains(rule_name),
+            UnknownRuleSnafu { rule_name: String::from_utf8_lossy(rule_name) }
+        );
+        Ok(())
+    }
+
+    fn add_edge(&mut self) {
+        self.edges.push(ValidationEdge::default());
+    }
+
+    fn add_output(&mut self, output: &[u8]) -> Result<(), Error> {
+        ensure!(
+            self.outputs.insert(output.to_vec()),
+            DuplicateOutputSnafu { output: String::from_utf8_lossy(output) }
+        );
+        if let Some(edge) = self.edges.last_mut() {
+            edge.outputs.push(output.to_vec());
+        }
+        Ok(())
+    }
+
+    fn add_dependency(&mut self, dependency: &[u8]) {
+        if let Some(edge) = self.edges.last_mut() {
+            edge.dependencies.push(dependency.to_vec());
+        }
+    }
+}
+
+impl Default for Validation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Part of the first line of a build definition which contains a list of paths
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Outputs,
+    ImplicitOutputs,
+    Inputs,
+    ImplicitDependencies,
+    OrderOnlyDependencies,
+    Validations,
+}
+
+impl Section {
+    /// What is written before the first path of the section
+    const fn separator(self) -> &'static [u8] {
+        match self {
+            Self::Outputs | Self::Inputs => b" ",
+            Self::ImplicitOutputs | Self::ImplicitDependencies => b" | ",
+            Self::OrderOnlyDependencies => b" || ",
+            Self::Validations => b" |@ ",
+        }
+    }
 }
 
 impl<W: Write> NinjaWriter<W> {
     pub const fn new(config: Config, writer: W) -> Self {
-        Self { config, writer, current_line_size: 0 }
+        Self {
+            config,
+            writer,
+            current_line_size: 0,
+            current_section: Section::Outputs,
+            build_buffer: None,
+            validation: None,
+            recorded_edges: None,
+        }
+    }
+
+    /// Check the next definitions with `validation`, which may already contain the definitions of
+    /// another file.
+    pub fn enable_validation(&mut self, validation: Validation) {
+        self.validation = Some(validation);
+    }
+
+    /// Stop checking the next definitions and get the graph checked so far.
+    pub const fn take_validation(&mut self) -> Option<Validation> {
+        self.validation.take()
+    }
+
+    /// Record the next build definitions in `edges`, which may already contain the ones of another
+    /// file.
+    pub fn record_edges(&mut self, edges: Vec<Edge>) {
+        self.recorded_edges = Some(edges);
+    }
+
+    /// Stop recording the next build definitions and get the edges recorded so far.
+    pub const fn take_recorded_edges(&mut self) -> Option<Vec<Edge>> {
+        self.recorded_edges.take()
+    }
+
+    /// Update the edge of the current build definition, if the edges are recorded.
+    fn record(&mut self, update: impl FnOnce(&mut Edge)) {
+        if let Some(edge) = self.recorded_edges.as_mut().and_then(|edges| edges.last_mut()) {
+            update(edge);
+        }
+    }
+
+    /// Get the buffer of the current build definition, or the writer outside of a build definition.
+    fn sink(&mut self) -> &mut dyn Write {
+        match &mut self.build_buffer {
+            Some(buffer) => buffer,
+            None => &mut self.writer,
+        }
+    }
+
+    pub(crate) const fn writer_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// Write a top-level variable: <https://ninja-build.org/manual.html#_variables>
+    ///
+    /// # Panics
+    ///
+    /// Panics if the previous definition has not been ended.
+    pub fn variable(
+        &mut self,
+        variable: impl AsRef<[u8]>,
+        value: impl Into<Expr>,
+    ) -> Result<(), Error> {
+        assert_eq!(self.current_line_size, 0);
+        let (variable, value) = (variable.as_ref(), value.into());
+        let value = check_expr(variable, &value)?;
+        self.current_line_size = variable.len() + 3;
+        self.writer
+            .write_all(variable)
+            .and_then(|()| self.writer.write_all(b" = "))
+            .and_then(|()| self.write_expr(&value))
+            .and_then(|()| self.writer.write_all(b"\n"))
+            .with_context(|_| VariableSnafu {
+                variable: String::from_utf8_lossy(variable),
+                value: value.to_string_lossy(),
+            })?;
+        self.current_line_size = 0;
+        Ok(())
+    }
+
+    /// Write targets built by default:
+    /// <https://ninja-build.org/manual.html#_default_target_statements>
+    ///
+    /// # Panics
+    ///
+    /// Panics if the previous definition has not been ended.
+    pub fn default(&mut self, target: impl NinjaPath) -> Result<AfterDefault<'_, W>, Error> {
+        assert_eq!(self.current_line_size, 0);
+        self.writer.write_all(b"default").context(DefaultSnafu)?;
+        self.current_line_size = 7;
+        self.write_default_target(path_bytes(&target)?)
+    }
+
+    fn write_default_target(&mut self, target: &[u8]) -> Result<AfterDefault<'_, W>, Error> {
+        check_path(target)?;
+        self.writer
+            .write_all(b" ")
+            .with_context(|_| DefaultTargetSnafu { target: String::from_utf8_lossy(target) })?;
+        self.current_line_size += 1;
+        self.write_escaped_path(target)
+            .with_context(|_| DefaultTargetSnafu { target: String::from_utf8_lossy(target) })?;
+        Ok(AfterDefault(self))
+    }
+
+    fn write_default_end(&mut self) -> Result<(), Error> {
+        self.writer.write_all(b"\n").context(DefaultEndSnafu)?;
+        self.current_line_size = 0;
+        Ok(())
+    }
+
+    /// Include another Ninja file in the current scope:
+    /// <https://ninja-build.org/manual.html#ref_scope>
+    ///
+    /// # Panics
+    ///
+    /// Panics if the previous definition has not been ended.
+    pub fn include(&mut self, path: impl NinjaPath) -> Result<(), Error> {
+        assert_eq!(self.current_line_size, 0);
+        let path = path_bytes(&path)?;
+        check_path(path)?;
+        self.write_inclusion(b"include ", path)
+            .with_context(|_| IncludeSnafu { path: String::from_utf8_lossy(path) })?;
+        Ok(())
+    }
+
+    /// Include another Ninja file in a new scope: <https://ninja-build.org/manual.html#ref_scope>
+    ///
+    /// The included file can use the variables and rules of the current scope.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the previous definition has not been ended.
+    pub fn subninja(&mut self, path: impl NinjaPath) -> Result<(), Error> {
+        assert_eq!(self.current_line_size, 0);
+        let path = path_bytes(&path)?;
+        check_path(path)?;
+        self.write_inclusion(b"subninja ", path)
+            .with_context(|_| SubninjaSnafu { path: String::from_utf8_lossy(path) })?;
+        Ok(())
+    }
+
+    /// Write a comment. Each line of the text is wrapped at the width of the configuration,
+    /// except a word which is too long. The indentation of a line is kept, also on its wrapped
+    /// lines, but the other consecutive spaces are merged.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the previous definition has not been ended.
+    pub fn comment(&mut self, text: impl AsRef<[u8]>) -> Result<(), Error> {
+        assert_eq!(self.current_line_size, 0);
+        let text = text.as_ref();
+        // Ninja sees `b'\0'` as the end of the file.
+        ensure!(
+            !text.contains(&b'\0'),
+            UnrepresentableCommentSnafu { comment: String::from_utf8_lossy(text) }
+        );
+        self.write_comment(text)
+            .with_context(|_| CommentSnafu { comment: String::from_utf8_lossy(text) })?;
+        Ok(())
     }
 
+    fn write_comment(&mut self, text: &[u8]) -> io::Result<()> {
+        for line in text.split(|&byte| byte == b'\n') {
+            let (indentation, _) =
+                line.split_at(line.iter().take_while(|&&byte| byte == b' ').count());
+            self.writer.write_all(b"#")?;
+            let mut line_size = 1;
+            for word in line.split(|&byte| byte == b' ').filter(|word| !word.is_empty()) {
+                if line_size > 1 && line_size + 1 + word.len() > self.config.width {
+                    self.writer.write_all(b"\n#")?;
+                    line_size = 1;
+                }
+                self.writer.write_all(b" ")?;
+                if line_size == 1 {
+                    self.writer.write_all(indentation)?;
+                    line_size += indentation.len();
+                }
+                self.writer.write_all(word)?;
+                line_size += 1 + word.len();
+            }
+            self.writer.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    /// Write an empty line, for example to separate groups of definitions.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the previous definition has not been ended.
+    pub fn blank_line(&mut self) -> Result<(), Error> {
+        assert_eq!(self.current_line_size, 0);
+        self.writer.write_all(b"\n").context(BlankLineSnafu)?;
+        Ok(())
+    }
+
+    fn write_inclusion(&mut self, keyword: &[u8], path: &[u8]) -> io::Result<()> {
+        self.writer.write_all(keyword)?;
+        self.current_line_size = keyword.len();
+        self.write_escaped_path(path)?;
+        self.writer.write_all(b"\n")?;
+        self.current_line_size = 0;
+        Ok(())
+    }
+
+    /// Declare a pool: <https://ninja-build.org/manual.html#ref_pool>
+    ///
+    /// Do not declare [`CONSOLE_POOL`], which is built in.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the previous definition has not been ended.
+    pub fn pool(&mut self, pool_name: impl AsRef<[u8]>) -> Result<AfterPool<'_, W>, Error> {
+        assert_eq!(self.current_line_size, 0);
+        let pool_name = pool_name.as_ref();
+        self.writer
+            .write_all(b"pool ")
+            .and_then(|()| self.writer.write_all(pool_name))
+            .with_context(|_| PoolSnafu { pool_name: String::from_utf8_lossy(pool_name) })?;
+        self.current_line_size = 5 + pool_name.len();
+        Ok(AfterPool(self))
+    }
+
+    fn write_depth(&mut self, depth: usize) -> Result<AfterDepth<'_, W>, Error> {
+        let depth_text = depth.to_string();
+        self.writer
+            .write_all(b"\n  depth = ")
+            .and_then(|()| self.writer.write_all(depth_text.as_bytes()))
+            .context(DepthSnafu { depth })?;
+        self.current_line_size = 10 + depth_text.len();
+        Ok(AfterDepth(self))
+    }
+
+    fn write_pool_end(&mut self) -> Result<(), Error> {
+        self.writer.write_all(b"\n").context(PoolEndSnafu)?;
+        self.current_line_size = 0;
+        Ok(())
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the previous definition has not been ended.
     pub fn rule(&mut self, rule_name: impl AsRef<[u8]>) -> Result<AfterRule<'_, W>, Error> {
         assert_eq!(self.current_line_size, 0);
         let rule_name = rule_name.as_ref();
@@ -89,184 +861,399 @@ impl<W: Write> NinjaWriter<W> {
             .and_then(|()| self.writer.write_all(rule_name))
             .with_context(|_| RuleSnafu { rule_name: String::from_utf8_lossy(rule_name) })?;
         self.current_line_size = 5 + rule_name.len();
+        if let Some(validation) = &mut self.validation {
+            validation.add_rule(rule_name);
+        }
         Ok(AfterRule(self))
     }
 
-    fn write_command(&mut self, command: &[u8]) -> Result<AfterCommand<'_, W>, Error> {
+    fn write_command(&mut self, command: &Expr) -> Result<AfterCommand<'_, W>, Error> {
+        let command = check_expr(b"command", command)?;
+        self.current_line_size = 12;
         self.writer
             .write_all(b"\n  command = ")
-            .and_then(|()| self.writer.write_all(command))
-            .with_context(|_| CommandSnafu { command: String::from_utf8_lossy(command) })?;
-        self.current_line_size = 12 + command.len();
+            .and_then(|()| self.write_expr(&command))
+            .with_context(|_| CommandSnafu { command: command.to_string_lossy() })?;
         Ok(AfterCommand(self))
     }
 
+    fn write_rule_variable(&mut self, variable: &'static str, value: &Expr) -> Result<(), Error> {
+        let value = check_expr(variable.as_bytes(), value)?;
+        self.current_line_size = 5 + variable.len();
+        self.writer
+            .write_all(b"\n  ")
+            .and_then(|()| self.writer.write_all(variable.as_bytes()))
+            .and_then(|()| self.writer.write_all(b" = "))
+            .and_then(|()| self.write_expr(&value))
+            .with_context(|_| RuleVariableSnafu { variable, value: value.to_string_lossy() })?;
+        Ok(())
+    }
+
     fn write_rule_end(&mut self) -> Result<(), Error> {
         self.writer.write_all(b"\n").context(RuleEndSnafu)?;
         self.current_line_size = 0;
         Ok(())
     }
 
+    /// # Panics
+    ///
+    /// Panics if the previous definition has not been ended.
     pub fn build(&mut self) -> Result<AfterBuild<'_, W>, Error> {
         assert_eq!(self.current_line_size, 0);
-        self.writer.write_all(b"build").context(BeginningSnafu)?;
+        self.build_buffer = Some(b"build".to_vec());
         self.current_line_size = 5;
+        self.current_section = Section::Outputs;
+        if let Some(validation) = &mut self.validation {
+            validation.add_edge();
+        }
+        if let Some(edges) = &mut self.recorded_edges {
+            edges.push(Edge::default());
+        }
         Ok(AfterBuild(self))
     }
 
     fn write_output(&mut self, output: &[u8]) -> Result<AfterOutput<'_, W>, Error> {
-        self.writer
-            .write_all(b" ")
-            .with_context(|_| OutputSnafu { output: String::from_utf8_lossy(output) })?;
-        self.current_line_size += 1;
-        self.write_escaped_path(output)
+        check_path(output)?;
+        if let Some(validation) = &mut self.validation {
+            validation.add_output(output)?;
+        }
+        self.record(|edge| edge.outputs.push(String::from_utf8_lossy(output).into_owned()));
+        self.write_path_in(Section::Outputs, output)
             .with_context(|_| OutputSnafu { output: String::from_utf8_lossy(output) })?;
         Ok(AfterOutput(self))
     }
 
+    fn write_implicit_output(
+        &mut self,
+        output: &[u8],
+    ) -> Result<AfterImplicitOutput<'_, W>, Error> {
+        check_path(output)?;
+        if let Some(validation) = &mut self.validation {
+            validation.add_output(output)?;
+        }
+        self.record(|edge| {
+            edge.implicit_outputs.push(String::from_utf8_lossy(output).into_owned());
+        });
+        self.write_path_in(Section::ImplicitOutputs, output)
+            .with_context(|_| ImplicitOutputSnafu { output: String::from_utf8_lossy(output) })?;
+        Ok(AfterImplicitOutput(self))
+    }
+
     fn write_rule(&mut self, rule_name: &[u8]) -> Result<AfterBuildRule<'_, W>, Error> {
-        self.writer
+        if let Some(validation) = &self.validation {
+            validation.check_rule(rule_name)?;
+        }
+        self.record(|edge| edge.rule = String::from_utf8_lossy(rule_name).into_owned());
+        self.sink()
             .write_all(b": ")
             .with_context(|_| BuildRuleSnafu { rule_name: String::from_utf8_lossy(rule_name) })?;
         self.current_line_size += 2;
         self.write_unescaped_text(rule_name)
             .with_context(|_| BuildRuleSnafu { rule_name: String::from_utf8_lossy(rule_name) })?;
+        self.current_section = Section::Inputs;
         Ok(AfterBuildRule(self))
     }
 
     fn write_input(&mut self, input: &[u8]) -> Result<AfterInput<'_, W>, Error> {
-        self.writer
-            .write_all(b" ")
-            .with_context(|_| InputSnafu { input: String::from_utf8_lossy(input) })?;
-        self.current_line_size += 1;
-        self.write_escaped_path(input)
+        check_path(input)?;
+        if let Some(validation) = &mut self.validation {
+            validation.add_dependency(input);
+        }
+        self.record(|edge| edge.inputs.push(String::from_utf8_lossy(input).into_owned()));
+        self.write_path_in(Section::Inputs, input)
             .with_context(|_| InputSnafu { input: String::from_utf8_lossy(input) })?;
         Ok(AfterInput(self))
     }
 
-    fn write_first_implicit_dependency(
+    fn write_implicit_dependency(
         &mut self,
         dependency: &[u8],
     ) -> Result<AfterImplicitDependency<'_, W>, Error> {
-        self.writer.write_all(b" | ").with_context(|_| ImplicitDependencySnafu {
-            dependency: String::from_utf8_lossy(dependency),
-        })?;
-        self.current_line_size += 3;
-        self.write_escaped_path(dependency).with_context(|_| ImplicitDependencySnafu {
-            dependency: String::from_utf8_lossy(dependency),
+        check_path(dependency)?;
+        if let Some(validation) = &mut self.validation {
+            validation.add_dependency(dependency);
+        }
+        self.record(|edge| {
+            edge.implicit_dependencies.push(String::from_utf8_lossy(dependency).into_owned());
+        });
+        self.write_path_in(Section::ImplicitDependencies, dependency).with_context(|_| {
+            ImplicitDependencySnafu { dependency: String::from_utf8_lossy(dependency) }
         })?;
         Ok(AfterImplicitDependency(self))
     }
 
-    fn write_extra_implicit_dependency(
+    fn write_order_only_dependency(
         &mut self,
         dependency: &[u8],
-    ) -> Result<AfterImplicitDependency<'_, W>, Error> {
-        self.writer.write_all(b" ").with_context(|_| ImplicitDependencySnafu {
-            dependency: String::from_utf8_lossy(dependency),
-        })?;
-        self.current_line_size += 1;
-        self.write_escaped_path(dependency).with_context(|_| ImplicitDependencySnafu {
-            dependency: String::from_utf8_lossy(dependency),
+    ) -> Result<AfterOrderOnlyDependency<'_, W>, Error> {
+        check_path(dependency)?;
+        if let Some(validation) = &mut self.validation {
+            validation.add_dependency(dependency);
+        }
+        self.record(|edge| {
+            edge.order_only_dependencies.push(String::from_utf8_lossy(dependency).into_owned());
+        });
+        self.write_path_in(Section::OrderOnlyDependencies, dependency).with_context(|_| {
+            OrderOnlyDependencySnafu { dependency: String::from_utf8_lossy(dependency) }
         })?;
-        Ok(AfterImplicitDependency(self))
+        Ok(AfterOrderOnlyDependency(self))
     }
 
-    fn write_first_order_only_dependency(
-        &mut self,
-        dependency: &[u8],
-    ) -> Result<AfterOrderOnlyDependency<'_, W>, Error> {
-        self.writer.write_all(b" || ").with_context(|_| OrderOnlyDependencySnafu {
-            dependency: String::from_utf8_lossy(dependency),
+    fn write_validation(&mut self, validation: &[u8]) -> Result<AfterValidation<'_, W>, Error> {
+        check_path(validation)?;
+        self.record(|edge| edge.validations.push(String::from_utf8_lossy(validation).into_owned()));
+        self.write_path_in(Section::Validations, validation).with_context(|_| ValidationSnafu {
+            validation: String::from_utf8_lossy(validation),
         })?;
-        self.current_line_size += 3;
-        self.write_escaped_path(dependency).with_context(|_| OrderOnlyDependencySnafu {
-            dependency: String::from_utf8_lossy(dependency),
-        })?;
-        Ok(AfterOrderOnlyDependency(self))
+        Ok(AfterValidation(self))
+    }
+
+    /// Write a path in a section of the first line of a build definition. The separator of the
+    /// section is written before the first path of the section only, so a section without paths
+    /// leaves no trace.
+    fn write_path_in(&mut self, section: Section, path: &[u8]) -> io::Result<()> {
+        let separator = if self.current_section == section { b" " } else { section.separator() };
+        self.sink().write_all(separator)?;
+        self.current_line_size += separator.len();
+        self.current_section = section;
+        self.write_escaped_path(path)
+    }
+
+    fn write_dyndep(&mut self, dyndep: &[u8]) -> Result<AfterDyndep<'_, W>, Error> {
+        check_path(dyndep)?;
+        self.record(|edge| {
+            edge.variables.insert("dyndep".into(), String::from_utf8_lossy(dyndep).into_owned());
+        });
+        self.sink()
+            .write_all(b"\n  dyndep = ")
+            .with_context(|_| DyndepSnafu { dyndep: String::from_utf8_lossy(dyndep) })?;
+        self.current_line_size = 11;
+        self.write_escaped_path(dyndep)
+            .with_context(|_| DyndepSnafu { dyndep: String::from_utf8_lossy(dyndep) })?;
+        Ok(AfterDyndep(self))
+    }
+
+    fn write_build_pool(&mut self, pool_name: &[u8]) -> Result<AfterBuildPool<'_, W>, Error> {
+        self.write_build_variable(b"pool", &Expr::from(pool_name))?;
+        Ok(AfterBuildPool(self))
     }
 
     fn write_variable_and_value(
         &mut self,
         variable: &[u8],
-        value: &[u8],
+        value: &Expr,
     ) -> Result<AfterVariableAndValue<'_, W>, Error> {
-        for bytes in [b"\n  ", variable, b" = ", value] {
-            self.writer.write_all(bytes).with_context(|_| VariableAndValueSnafu {
+        self.write_build_variable(variable, value)?;
+        Ok(AfterVariableAndValue(self))
+    }
+
+    fn write_build_variable(&mut self, variable: &[u8], value: &Expr) -> Result<(), Error> {
+        self.record(|edge| {
+            edge.variables.insert(String::from_utf8_lossy(variable).into_owned(), value.to_text());
+        });
+        let value = check_expr(variable, value)?;
+        self.current_line_size = 5 + variable.len();
+        self.sink()
+            .write_all(b"\n  ")
+            .and_then(|()| self.sink().write_all(variable))
+            .and_then(|()| self.sink().write_all(b" = "))
+            .and_then(|()| self.write_expr(&value))
+            .with_context(|_| VariableAndValueSnafu {
                 variable: String::from_utf8_lossy(variable),
-                value: String::from_utf8_lossy(value),
+                value: value.to_string_lossy(),
             })?;
-        }
-        self.current_line_size = 5 + variable.len() + value.len();
-        Ok(AfterVariableAndValue(self))
+        Ok(())
     }
 
     fn write_build_end(&mut self) -> Result<(), Error> {
-        self.writer.write_all(b"\n").context(BuildEndSnafu)?;
+        let mut build_definition = self.build_buffer.take().unwrap_or_default();
+        build_definition.push(b'\n');
+        self.writer.write_all(&build_definition).context(BuildEndSnafu)?;
         self.current_line_size = 0;
         Ok(())
     }
 
     fn write_unescaped_text(&mut self, text: &[u8]) -> io::Result<()> {
         let text_size = text.len();
-        // "+ 2" because, in the worst case, the text could be followed by " $".
-        if self.current_line_size + text_size + 2 > self.config.width {
-            self.writer.write_all(b"$\n  ")?;
+        // "+ 5" because, in the worst case, the text could be followed by " || $".
+        if self.current_line_size + text_size + 5 > self.config.width {
+            self.sink().write_all(b"$\n  ")?;
             self.current_line_size = 2;
         }
-        self.writer.write_all(text)?;
+        self.sink().write_all(text)?;
         self.current_line_size += text_size;
         Ok(())
     }
 
-    /// Write an escaped path by adding `b'$'` before the bytes in `b"$ :|#\n"`.
+    /// Write an escaped expression, with line continuations where the line would be too long.
+    ///
+    /// Like `ninja_syntax.py`, a line is continued after a space. The first line can also be
+    /// continued before the expression, after `=`. A word too long for a line is cut anywhere and,
+    /// because Ninja skips the spaces at the beginning of the next line, a space which begins it is
+    /// escaped.
+    fn write_expr(&mut self, expr: &EscapedExpr) -> io::Result<()> {
+        let token_count = expr.token_starts.len();
+        let mut start = 0;
+        let mut is_first_line = true;
+        while start < token_count {
+            let rest_size = expr.bytes.len() - expr.token_starts[start];
+            if self.current_line_size + rest_size <= self.config.width {
+                break;
+            }
+            // "+ 1" for the `$` of the line continuation.
+            let available_size = self.config.width.saturating_sub(self.current_line_size + 1);
+            let mut size = 0;
+            let mut cut_in_word = None;
+            let mut cut_after_space = is_first_line.then_some(start);
+            for end in start + 1..token_count {
+                size += expr.token(end - 1).len();
+                if size > available_size {
+                    break;
+                }
+                cut_in_word = Some(end);
+                if expr.token(end - 1) == b" " && expr.token(end) != b" " {
+                    cut_after_space = Some(end);
+                }
+            }
+            let end = cut_after_space.or(cut_in_word).unwrap_or(start + 1);
+            if end == token_count {
+                break;
+            }
+            self.sink().write_all(&expr.bytes[expr.token_starts[start]..expr.token_starts[end]])?;
+            self.sink().write_all(b"$\n    ")?;
+            self.current_line_size = 4;
+            if expr.token(end) == b" " {
+                self.sink().write_all(b"$")?;
+                self.current_line_size += 1;
+            }
+            start = end;
+            is_first_line = false;
+        }
+        let rest = &expr.bytes[expr.token_starts.get(start).copied().unwrap_or(expr.bytes.len())..];
+        self.sink().write_all(rest)?;
+        self.current_line_size += rest.len();
+        Ok(())
+    }
+
+    /// Write an escaped path by adding `b'$'` before the bytes in `b"$ :"`.
     ///
     /// In the GitHub repository of Ninja, `ninja_syntax.py` escapes `'$'`, `' '` and `':'`:
     /// <https://github.com/ninja-build/ninja/blob/v1.11.1/misc/ninja_syntax.py#L27-L28>
     ///
-    /// `b'|'` must be escaped too. Otherwise, a file called `|` or  `||` could be seen as a
-    /// separator: <https://ninja-build.org/manual.html#ref_ninja_file>
-    ///
-    /// `b'#'` must be escaped too. The Ninja documentation says "Comments begin with # and extend to
-    /// the end of the line.": <https://ninja-build.org/manual.html#ref_lexer>
-    ///
-    /// `b'\n'` must be escaped too. The Ninja documentation says: "Newlines are significant.":
-    /// <https://ninja-build.org/manual.html#ref_lexer>
+    /// `b'#'` does not need to be escaped: Ninja sees a comment only where a token begins, never
+    /// inside a path. The other special bytes cannot be escaped, so the caller must have called
+    /// [`check_path`].
     fn write_escaped_path(&mut self, path: &[u8]) -> io::Result<()> {
-        let escaped_path_size = path.len()
-            + path
-                .iter()
-                .filter(|byte| matches!(byte, b'$' | b' ' | b':' | b'|' | b'#' | b'\n'))
-                .count();
+        let escaped_path_size =
+            path.len() + path.iter().filter(|byte| matches!(byte, b'$' | b' ' | b':')).count();
         // "+ 5" because, in the worst case, the path could be followed by " || $".
         if self.current_line_size + escaped_path_size + 5 > self.config.width {
-            self.writer.write_all(b"$\n  ")?;
+            self.sink().write_all(b"$\n  ")?;
             self.current_line_size = 2;
         }
         for &byte in path {
-            match byte {
-                b'$' | b' ' | b':' | b'|' | b'#' | b'\n' => self.writer.write_all(b"$")?,
-                _ => (),
+            if matches!(byte, b'$' | b' ' | b':') {
+                self.sink().write_all(b"$")?;
             }
-            self.writer.write_all(&[byte])?;
+            self.sink().write_all(&[byte])?;
         }
         self.current_line_size += escaped_path_size;
         Ok(())
     }
 }
 
+/// Check that a path can be written in a Ninja file.
+///
+/// Ninja has no escape sequence for `b'|'`, which would be seen as a separator, nor for `b'\n'`
+/// and `b'\r'`, which would end the line (`$` followed by a newline is a line continuation), nor
+/// for `b'\0'`, which Ninja sees as the end of the file. An empty path would be seen as the end of
+/// a list of paths. <https://ninja-build.org/manual.html#ref_lexer>
+fn check_path(path: &[u8]) -> Result<(), Error> {
+    ensure!(
+        !path.is_empty() && !path.iter().any(|byte| matches!(byte, b'\0' | b'|' | b'\n' | b'\r')),
+        UnrepresentablePathSnafu { path: String::from_utf8_lossy(path) }
+    );
+    Ok(())
+}
+
+fn path_bytes<P: NinjaPath + ?Sized>(path: &P) -> Result<&[u8], Error> {
+    let bytes =
+        path.ninja_bytes().with_context(|| NonUtf8PathSnafu { path: path.to_string_lossy() })?;
+    Ok(bytes)
+}
+
+/// Check that an expression can be written in a Ninja file and escape it.
+///
+/// Like in a path, `b'\0'`, `b'\n'` and `b'\r'` cannot be escaped. A reference must be a valid
+/// variable name. <https://ninja-build.org/manual.html#ref_lexer>
+fn check_expr(variable: &[u8], expr: &Expr) -> Result<EscapedExpr, Error> {
+    for piece in &expr.0 {
+        match piece {
+            ExprPiece::Text(text) => ensure!(
+                !text.iter().any(|byte| matches!(byte, b'\0' | b'\n' | b'\r')),
+                UnrepresentableValueSnafu {
+                    variable: String::from_utf8_lossy(variable),
+                    value: String::from_utf8_lossy(text),
+                }
+            ),
+            ExprPiece::Variable(name) => ensure!(
+                !name.is_empty()
+                    && name.bytes().all(|byte| is_simple_name_byte(byte) || byte == b'.'),
+                InvalidReferenceSnafu {
+                    variable: String::from_utf8_lossy(variable),
+                    reference: name,
+                }
+            ),
+        }
+    }
+    Ok(expr.escape())
+}
+
+#[must_use]
+pub struct AfterDefault<'a, W: Write>(&'a mut NinjaWriter<W>);
+
+#[must_use]
+pub struct AfterPool<'a, W: Write>(&'a mut NinjaWriter<W>);
+
+#[must_use]
+pub struct AfterDepth<'a, W: Write>(&'a mut NinjaWriter<W>);
+
 #[must_use]
 pub struct AfterRule<'a, W: Write>(&'a mut NinjaWriter<W>);
 
 #[must_use]
 pub struct AfterCommand<'a, W: Write>(&'a mut NinjaWriter<W>);
 
+#[must_use]
+pub struct AfterDescription<'a, W: Write>(&'a mut NinjaWriter<W>);
+
+#[must_use]
+pub struct AfterDepfile<'a, W: Write>(&'a mut NinjaWriter<W>);
+
+#[must_use]
+pub struct AfterDeps<'a, W: Write>(&'a mut NinjaWriter<W>);
+
+#[must_use]
+pub struct AfterRestat<'a, W: Write>(&'a mut NinjaWriter<W>);
+
+#[must_use]
+pub struct AfterGenerator<'a, W: Write>(&'a mut NinjaWriter<W>);
+
+#[must_use]
+pub struct AfterRspfile<'a, W: Write>(&'a mut NinjaWriter<W>);
+
+#[must_use]
+pub struct AfterRulePool<'a, W: Write>(&'a mut NinjaWriter<W>);
+
 #[must_use]
 pub struct AfterBuild<'a, W: Write>(&'a mut NinjaWriter<W>);
 
 #[must_use]
 pub struct AfterOutput<'a, W: Write>(&'a mut NinjaWriter<W>);
 
+#[must_use]
+pub struct AfterImplicitOutput<'a, W: Write>(&'a mut NinjaWriter<W>);
+
 #[must_use]
 pub struct AfterBuildRule<'a, W: Write>(&'a mut NinjaWriter<W>);
 
@@ -280,200 +1267,611 @@ pub struct AfterImplicitDependency<'a, W: Write>(&'a mut NinjaWriter<W>);
 pub struct AfterOrderOnlyDependency<'a, W: Write>(&'a mut NinjaWriter<W>);
 
 #[must_use]
-pub struct AfterVariableAndValue<'a, W: Write>(&'a mut NinjaWriter<W>);
+pub struct AfterValidation<'a, W: Write>(&'a mut NinjaWriter<W>);
 
 #[must_use]
-pub enum AfterBuildRuleOrInput<'a, W: Write> {
-    AfterBuildRule(AfterBuildRule<'a, W>),
-    AfterInput(AfterInput<'a, W>),
-}
+pub struct AfterDyndep<'a, W: Write>(&'a mut NinjaWriter<W>);
 
 #[must_use]
-pub enum AfterInputOrImplicitDependency<'a, W: Write> {
-    AfterInput(AfterInput<'a, W>),
-    AfterImplicitDependency(AfterImplicitDependency<'a, W>),
-}
+pub struct AfterBuildPool<'a, W: Write>(&'a mut NinjaWriter<W>);
 
-impl<'a, W: Write> AfterRule<'a, W> {
-    pub fn command(self, command: impl AsRef<[u8]>) -> Result<AfterCommand<'a, W>, Error> {
-        self.0.write_command(command.as_ref())
+#[must_use]
+pub struct AfterVariableAndValue<'a, W: Write>(&'a mut NinjaWriter<W>);
+
+impl<W: Write> AfterDefault<'_, W> {
+    pub fn target(self, target: impl NinjaPath) -> Result<Self, Error> {
+        self.0.write_default_target(path_bytes(&target)?)
     }
-}
 
-impl<W: Write> AfterCommand<'_, W> {
-    pub fn end(self) -> Result<(), Error> {
-        self.0.write_rule_end()
+    pub fn targets(
+        mut self,
+        targets: impl IntoIterator<Item = impl NinjaPath>,
+    ) -> Result<Self, Error> {
+        for target in targets {
+            self = self.target(target)?;
+        }
+        Ok(self)
     }
-}
 
-impl<'a, W: Write> AfterBuild<'a, W> {
-    pub fn output(self, output: impl AsRef<[u8]>) -> Result<AfterOutput<'a, W>, Error> {
-        self.0.write_output(output.as_ref())
+    pub fn end(self) -> Result<(), Error> {
+        self.0.write_default_end()
     }
+}
 
-    #[cfg(unix)]
-    pub fn unix_output(self, output: impl AsRef<OsStr>) -> Result<AfterOutput<'a, W>, Error> {
-        let output = std::os::unix::ffi::OsStrExt::as_bytes(output.as_ref());
-        self.0.write_output(output)
+impl<'a, W: Write> AfterPool<'a, W> {
+    pub fn depth(self, depth: usize) -> Result<AfterDepth<'a, W>, Error> {
+        self.0.write_depth(depth)
     }
 }
 
-impl<'a, W: Write> AfterOutput<'a, W> {
-    pub fn rule(self, rule_name: impl AsRef<[u8]>) -> Result<AfterBuildRule<'a, W>, Error> {
-        self.0.write_rule(rule_name.as_ref())
+impl<W: Write> AfterDepth<'_, W> {
+    pub fn end(self) -> Result<(), Error> {
+        self.0.write_pool_end()
     }
 }
 
-impl<'a, W: Write> AfterBuildRule<'a, W> {
-    pub fn input(self, input: impl AsRef<[u8]>) -> Result<AfterInput<'a, W>, Error> {
-        self.0.write_input(input.as_ref())
+impl<'a, W: Write> AfterRule<'a, W> {
+    pub fn command(self, command: impl Into<Expr>) -> Result<AfterCommand<'a, W>, Error> {
+        self.0.write_command(&command.into())
     }
+}
 
-    pub fn inputs(
-        self,
-        inputs: impl IntoIterator<Item = impl AsRef<[u8]>>,
-    ) -> Result<AfterBuildRuleOrInput<'a, W>, Error> {
-        let mut inputs = inputs.into_iter();
-        if let Some(input) = inputs.next() {
-            let step = self.input(input)?;
-            let step = step.inputs(inputs)?;
-            Ok(AfterBuildRuleOrInput::AfterInput(step))
-        } else {
-            Ok(AfterBuildRuleOrInput::AfterBuildRule(self))
+/// Implement, on a typestate, the steps which can follow it.
+///
+/// In a rule definition, the variables after `command` are optional, but they must be written in
+/// a fixed order. In a build definition, the lists of paths after the outputs and the rule are
+/// optional too. So each typestate has one method per step which can still be written.
+macro_rules! impl_steps {
+    ($state:ident { $($step:ident),* $(,)? }) => {
+        impl<'a, W: Write> $state<'a, W> {
+            $(impl_steps!(@$step);)*
         }
-    }
+    };
+    (@description) => {
+        pub fn description(
+            self,
+            description: impl Into<Expr>,
+        ) -> Result<AfterDescription<'a, W>, Error> {
+            self.0.write_rule_variable("description", &description.into())?;
+            Ok(AfterDescription(self.0))
+        }
+    };
+    (@depfile) => {
+        pub fn depfile(self, depfile: impl Into<Expr>) -> Result<AfterDepfile<'a, W>, Error> {
+            self.0.write_rule_variable("depfile", &depfile.into())?;
+            Ok(AfterDepfile(self.0))
+        }
+    };
+    (@deps) => {
+        pub fn deps(self, deps: Deps) -> Result<AfterDeps<'a, W>, Error> {
+            self.0.write_rule_variable("deps", &deps.as_bytes().into())?;
+            Ok(AfterDeps(self.0))
+        }
+    };
+    (@restat) => {
+        pub fn restat(self) -> Result<AfterRestat<'a, W>, Error> {
+            self.0.write_rule_variable("restat", &"1".into())?;
+            Ok(AfterRestat(self.0))
+        }
+    };
+    (@generator) => {
+        pub fn generator(self) -> Result<AfterGenerator<'a, W>, Error> {
+            self.0.write_rule_variable("generator", &"1".into())?;
+            Ok(AfterGenerator(self.0))
+        }
+    };
+    (@rspfile) => {
+        pub fn rspfile(
+            self,
+            rspfile: impl Into<Expr>,
+            rspfile_content: impl Into<Expr>,
+        ) -> Result<AfterRspfile<'a, W>, Error> {
+            self.0.write_rule_variable("rspfile", &rspfile.into())?;
+            self.0.write_rule_variable("rspfile_content", &rspfile_content.into())?;
+            Ok(AfterRspfile(self.0))
+        }
+    };
+    (@rule_pool) => {
+        pub fn pool(self, pool_name: impl AsRef<[u8]>) -> Result<AfterRulePool<'a, W>, Error> {
+            self.0.write_rule_variable("pool", &pool_name.as_ref().into())?;
+            Ok(AfterRulePool(self.0))
+        }
+    };
+    (@rule_end) => {
+        pub fn end(self) -> Result<(), Error> {
+            self.0.write_rule_end()
+        }
+    };
+    (@output) => {
+        pub fn output(self, output: impl NinjaPath) -> Result<AfterOutput<'a, W>, Error> {
+            self.0.write_output(path_bytes(&output)?)
+        }
+    };
+    (@outputs) => {
+        pub fn outputs(
+            self,
+            outputs: impl IntoIterator<Item = impl NinjaPath>,
+        ) -> Result<AfterOutput<'a, W>, Error> {
+            let mut step = AfterOutput(self.0);
+            for output in outputs {
+                step = step.output(output)?;
+            }
+            Ok(step)
+        }
+    };
+    (@implicit_output) => {
+        pub fn implicit_output(
+            self,
+            output: impl NinjaPath,
+        ) -> Result<AfterImplicitOutput<'a, W>, Error> {
+            self.0.write_implicit_output(path_bytes(&output)?)
+        }
+    };
+    (@implicit_outputs) => {
+        pub fn implicit_outputs(
+            self,
+            outputs: impl IntoIterator<Item = impl NinjaPath>,
+        ) -> Result<AfterImplicitOutput<'a, W>, Error> {
+            let mut step = AfterImplicitOutput(self.0);
+            for output in outputs {
+                step = step.implicit_output(output)?;
+            }
+            Ok(step)
+        }
+    };
+    (@build_rule) => {
+        pub fn rule(self, rule_name: impl AsRef<[u8]>) -> Result<AfterBuildRule<'a, W>, Error> {
+            self.0.write_rule(rule_name.as_ref())
+        }
+    };
+    (@input) => {
+        pub fn input(self, input: impl NinjaPath) -> Result<AfterInput<'a, W>, Error> {
+            self.0.write_input(path_bytes(&input)?)
+        }
+    };
+    (@inputs) => {
+        pub fn inputs(
+            self,
+            inputs: impl IntoIterator<Item = impl NinjaPath>,
+        ) -> Result<AfterInput<'a, W>, Error> {
+            let mut step = AfterInput(self.0);
+            for input in inputs {
+                step = step.input(input)?;
+            }
+            Ok(step)
+        }
+    };
+    (@input_results) => {
+        /// Write the inputs until an item of `inputs` is an error, which is returned.
+        pub fn input_results<E: error::Error + 'static>(
+            self,
+            inputs: impl IntoIterator<Item = Result<impl NinjaPath, E>>,
+        ) -> Result<AfterInput<'a, W>, ErrorOr<E>> {
+            let mut step = AfterInput(self.0);
+            for input in inputs {
+                let input = input.context(OtherSnafu)?;
+                step = step.input(input).context(DefinitionSnafu)?;
+            }
+            Ok(step)
+        }
+    };
+    (@implicit_dependency) => {
+        pub fn implicit_dependency(
+            self,
+            dependency: impl NinjaPath,
+        ) -> Result<AfterImplicitDependency<'a, W>, Error> {
+            self.0.write_implicit_dependency(path_bytes(&dependency)?)
+        }
+    };
+    (@implicit_dependencies) => {
+        pub fn implicit_dependencies(
+            self,
+            dependencies: impl IntoIterator<Item = impl NinjaPath>,
+        ) -> Result<AfterImplicitDependency<'a, W>, Error> {
+            let mut step = AfterImplicitDependency(self.0);
+            for dependency in dependencies {
+                step = step.implicit_dependency(dependency)?;
+            }
+            Ok(step)
+        }
+    };
+    (@order_only_dependency) => {
+        pub fn order_only_dependency(
+            self,
+            dependency: impl NinjaPath,
+        ) -> Result<AfterOrderOnlyDependency<'a, W>, Error> {
+            self.0.write_order_only_dependency(path_bytes(&dependency)?)
+        }
+    };
+    (@order_only_dependencies) => {
+        pub fn order_only_dependencies(
+            self,
+            dependencies: impl IntoIterator<Item = impl NinjaPath>,
+        ) -> Result<AfterOrderOnlyDependency<'a, W>, Error> {
+            let mut step = AfterOrderOnlyDependency(self.0);
+            for dependency in dependencies {
+                step = step.order_only_dependency(dependency)?;
+            }
+            Ok(step)
+        }
+    };
+    (@validation) => {
+        pub fn validation(
+            self,
+            validation: impl NinjaPath,
+        ) -> Result<AfterValidation<'a, W>, Error> {
+            self.0.write_validation(path_bytes(&validation)?)
+        }
+    };
+    (@validations) => {
+        pub fn validations(
+            self,
+            validations: impl IntoIterator<Item = impl NinjaPath>,
+        ) -> Result<AfterValidation<'a, W>, Error> {
+            let mut step = AfterValidation(self.0);
+            for validation in validations {
+                step = step.validation(validation)?;
+            }
+            Ok(step)
+        }
+    };
+    (@dyndep) => {
+        /// The dyndep file must also be an input of the build definition:
+        /// <https://ninja-build.org/manual.html#ref_dyndep>
+        pub fn dyndep(self, dyndep: impl NinjaPath) -> Result<AfterDyndep<'a, W>, Error> {
+            self.0.write_dyndep(path_bytes(&dyndep)?)
+        }
+    };
+    (@build_pool) => {
+        pub fn pool(self, pool_name: impl AsRef<[u8]>) -> Result<AfterBuildPool<'a, W>, Error> {
+            self.0.write_build_pool(pool_name.as_ref())
+        }
+    };
+    (@variable_and_value) => {
+        pub fn variable_and_value(
+            self,
+            variable: impl AsRef<[u8]>,
+            value: impl Into<Expr>,
+        ) -> Result<AfterVariableAndValue<'a, W>, Error> {
+            self.0.write_variable_and_value(variable.as_ref(), &value.into())
+        }
+    };
+    (@build_end) => {
+        pub fn end(self) -> Result<(), Error> {
+            self.0.write_build_end()
+        }
+    };
+}
+
+impl_steps!(AfterCommand {
+    description,
+    depfile,
+    deps,
+    restat,
+    generator,
+    rspfile,
+    rule_pool,
+    rule_end,
+});
+impl_steps!(AfterDescription { depfile, deps, restat, generator, rspfile, rule_pool, rule_end });
+impl_steps!(AfterDepfile { deps, restat, generator, rspfile, rule_pool, rule_end });
+impl_steps!(AfterDeps { restat, generator, rspfile, rule_pool, rule_end });
+impl_steps!(AfterRestat { generator, rspfile, rule_pool, rule_end });
+impl_steps!(AfterGenerator { rspfile, rule_pool, rule_end });
+impl_steps!(AfterRspfile { rule_pool, rule_end });
 
+impl<W: Write> AfterRulePool<'_, W> {
     pub fn end(self) -> Result<(), Error> {
-        self.0.write_build_end()
+        self.0.write_rule_end()
     }
 }
 
-impl<'a, W: Write> AfterInput<'a, W> {
-    fn input(self, input: impl AsRef<[u8]>) -> Result<Self, Error> {
-        self.0.write_input(input.as_ref())
+impl<'a, W: Write> AfterBuild<'a, W> {
+    pub fn output(self, output: impl NinjaPath) -> Result<AfterOutput<'a, W>, Error> {
+        self.0.write_output(path_bytes(&output)?)
     }
+}
 
-    pub fn inputs(
-        mut self,
-        inputs: impl IntoIterator<Item = impl AsRef<[u8]>>,
-    ) -> Result<Self, Error> {
-        for input in inputs {
-            self = self.input(input)?;
-        }
-        Ok(self)
-    }
+impl_steps!(AfterOutput { output, outputs, implicit_output, implicit_outputs, build_rule });
+impl_steps!(AfterImplicitOutput { implicit_output, implicit_outputs, build_rule });
+impl_steps!(AfterBuildRule {
+    input,
+    inputs,
+    input_results,
+    implicit_dependency,
+    implicit_dependencies,
+    order_only_dependency,
+    order_only_dependencies,
+    validation,
+    validations,
+    dyndep,
+    build_pool,
+    variable_and_value,
+    build_end,
+});
+impl_steps!(AfterInput {
+    input,
+    inputs,
+    input_results,
+    implicit_dependency,
+    implicit_dependencies,
+    order_only_dependency,
+    order_only_dependencies,
+    validation,
+    validations,
+    dyndep,
+    build_pool,
+    variable_and_value,
+    build_end,
+});
+impl_steps!(AfterImplicitDependency {
+    implicit_dependency,
+    implicit_dependencies,
+    order_only_dependency,
+    order_only_dependencies,
+    validation,
+    validations,
+    dyndep,
+    build_pool,
+    variable_and_value,
+    build_end,
+});
+impl_steps!(AfterOrderOnlyDependency {
+    order_only_dependency,
+    order_only_dependencies,
+    validation,
+    validations,
+    dyndep,
+    build_pool,
+    variable_and_value,
+    build_end,
+});
+impl_steps!(AfterValidation {
+    validation,
+    validations,
+    dyndep,
+    build_pool,
+    variable_and_value,
+    build_end,
+});
+impl_steps!(AfterDyndep { build_pool, variable_and_value, build_end });
+impl_steps!(AfterBuildPool { variable_and_value, build_end });
+impl_steps!(AfterVariableAndValue { variable_and_value, build_end });
 
-    #[cfg(unix)]
-    pub fn unix_input_results<E: error::Error + 'static>(
-        mut self,
-        inputs: impl IntoIterator<Item = Result<impl AsRef<OsStr>, E>>,
-    ) -> Result<Self, ErrorOr<E>> {
-        for input in inputs {
-            let input = input.context(OtherSnafu)?;
-            let input = std::os::unix::ffi::OsStrExt::as_bytes(input.as_ref());
-            self = self.input(input).context(DefinitionSnafu)?;
-        }
-        Ok(self)
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    fn implicit_dependency(
-        self,
-        dependency: impl AsRef<[u8]>,
-    ) -> Result<AfterImplicitDependency<'a, W>, Error> {
-        self.0.write_first_implicit_dependency(dependency.as_ref())
+    fn validating_writer() -> NinjaWriter<Vec<u8>> {
+        let mut ninja_writer = NinjaWriter::new(Config::with_width(80), Vec::new());
+        ninja_writer.enable_validation(Validation::new());
+        ninja_writer
     }
 
-    pub fn implicit_dependencies(
-        self,
-        dependencies: impl IntoIterator<Item = impl AsRef<[u8]>>,
-    ) -> Result<AfterInputOrImplicitDependency<'a, W>, Error> {
-        let mut dependencies = dependencies.into_iter();
-        if let Some(dependency) = dependencies.next() {
-            let step = self.implicit_dependency(dependency)?;
-            let step = step.implicit_dependencies(dependencies)?;
-            Ok(AfterInputOrImplicitDependency::AfterImplicitDependency(step))
-        } else {
-            Ok(AfterInputOrImplicitDependency::AfterInput(self))
-        }
+    #[test]
+    fn escape_and_wrap_expressions() {
+        let mut output = Vec::new();
+        let mut ninja_writer = NinjaWriter::new(Config::with_width(40), &mut output);
+        ninja_writer.variable("x", " $1").unwrap();
+        let command = Expr::new()
+            .text("echo ")
+            .variable("out")
+            .text("_1 ")
+            .variable("out")
+            .text(".d ")
+            .variable("in")
+            .text(" > /a/long/path/to/the/output");
+        ninja_writer.rule("r").unwrap().command(command).unwrap().end().unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "x = $ $$1\nrule r\n  command = echo ${out}_1 $out.d $in > $\n    /a/long/path/to/the/output\n"
+        );
     }
 
-    #[cfg(unix)]
-    fn unix_order_only_dependency(
-        self,
-        dependency: impl AsRef<OsStr>,
-    ) -> Result<AfterOrderOnlyDependency<'a, W>, Error> {
-        let dependency = std::os::unix::ffi::OsStrExt::as_bytes(dependency.as_ref());
-        self.0.write_first_order_only_dependency(dependency)
+    #[test]
+    fn reject_unrepresentable_expressions() {
+        let mut ninja_writer = NinjaWriter::new(Config::with_width(80), Vec::new());
+        let result = ninja_writer.variable("x", Expr::new().text("a\nb"));
+        assert!(matches!(
+            result,
+            Err(Error(InnerError::UnrepresentableValue { variable, value }))
+                if variable == "x" && value == "a\nb"
+        ));
+        let result = ninja_writer.variable("x", Expr::new().text("a").variable(""));
+        assert!(matches!(
+            result,
+            Err(Error(InnerError::InvalidReference { variable, reference }))
+                if variable == "x" && reference.is_empty()
+        ));
+        assert!(ninja_writer.writer.is_empty());
+        let rule = ninja_writer.rule("r").unwrap();
+        let result = rule.command(Expr::new().text("echo ").variable("a b"));
+        assert!(matches!(
+            result,
+            Err(Error(InnerError::InvalidReference { variable, reference }))
+                if variable == "command" && reference == "a b"
+        ));
     }
 
-    pub fn variable_and_value(
-        self,
-        variable: impl AsRef<[u8]>,
-        value: impl AsRef<[u8]>,
-    ) -> Result<AfterVariableAndValue<'a, W>, Error> {
-        self.0.write_variable_and_value(variable.as_ref(), value.as_ref())
+    #[test]
+    fn wrap_comments() {
+        let mut output = Vec::new();
+        let mut ninja_writer = NinjaWriter::new(Config::with_width(20), &mut output);
+        ninja_writer.comment("one two three four five six\n\nseven").unwrap();
+        ninja_writer.blank_line().unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "# one two three four\n# five six\n#\n# seven\n\n"
+        );
     }
 
-    pub fn end(self) -> Result<(), Error> {
-        self.0.write_build_end()
+    #[test]
+    fn keep_the_indentation_of_comments() {
+        let mut ninja_writer = NinjaWriter::new(Config::with_width(20), Vec::new());
+        ninja_writer.comment("list:\n  - one two  three four\n    five").unwrap();
+        assert_eq!(
+            String::from_utf8(ninja_writer.writer).unwrap(),
+            "# list:\n#   - one two three\n#   four\n#     five\n"
+        );
     }
-}
 
-impl<'a, W: Write> AfterImplicitDependency<'a, W> {
-    fn implicit_dependency(self, dependency: impl AsRef<[u8]>) -> Result<Self, Error> {
-        self.0.write_extra_implicit_dependency(dependency.as_ref())
+    fn write_output(output: impl NinjaPath) -> Result<Vec<u8>, Error> {
+        let mut ninja_writer = NinjaWriter::new(Config::with_width(80), Vec::new());
+        ninja_writer.build()?.output(output)?.rule("phony")?.end()?;
+        Ok(ninja_writer.writer)
     }
 
-    fn implicit_dependencies(
-        mut self,
-        dependencies: impl IntoIterator<Item = impl AsRef<[u8]>>,
-    ) -> Result<Self, Error> {
-        for dependency in dependencies {
-            self = self.implicit_dependency(dependency)?;
+    #[test]
+    fn write_the_same_bytes_for_each_kind_of_path() {
+        let expected = b"build dir/a$ b.o: phony\n";
+        let outputs = [
+            write_output("dir/a b.o"),
+            write_output(String::from("dir/a b.o")),
+            write_output(b"dir/a b.o"),
+            write_output(b"dir/a b.o".as_slice()),
+            write_output(b"dir/a b.o".to_vec()),
+            write_output(Utf8Path::new("dir/a b.o")),
+            write_output(Utf8PathBuf::from("dir/a b.o")),
+            write_output(OsStr::new("dir/a b.o")),
+            write_output(OsString::from("dir/a b.o")),
+            write_output(Path::new("dir/a b.o")),
+            write_output(PathBuf::from("dir/a b.o")),
+        ];
+        for output in outputs {
+            assert_eq!(output.unwrap(), expected);
         }
-        Ok(self)
     }
 
     #[cfg(unix)]
-    fn unix_order_only_dependency(
-        self,
-        dependency: impl AsRef<OsStr>,
-    ) -> Result<AfterOrderOnlyDependency<'a, W>, Error> {
-        let dependency = std::os::unix::ffi::OsStrExt::as_bytes(dependency.as_ref());
-        self.0.write_first_order_only_dependency(dependency)
+    #[test]
+    fn write_the_bytes_of_a_non_utf8_path_on_unix() {
+        use std::os::unix::ffi::OsStrExt as _;
+
+        let output = write_output(Path::new(OsStr::from_bytes(b"caf\xe9.o")));
+        assert_eq!(output.unwrap(), b"build caf\xe9.o: phony\n");
     }
-}
 
-impl<W: Write> AfterOrderOnlyDependency<'_, W> {
-    pub fn end(self) -> Result<(), Error> {
-        self.0.write_build_end()
+    #[test]
+    fn reject_a_path_without_bytes_for_ninja() {
+        /// Like a path which is not valid UTF-8 on a platform whose paths are not bytes
+        struct NonUtf8Path;
+
+        impl NinjaPath for NonUtf8Path {
+            fn ninja_bytes(&self) -> Option<&[u8]> {
+                None
+            }
+
+            fn to_string_lossy(&self) -> Cow<'_, str> {
+                Cow::Borrowed("caf\u{FFFD}.o")
+            }
+        }
+
+        let result = write_output(NonUtf8Path);
+        assert!(
+            matches!(result, Err(Error(InnerError::NonUtf8Path { path })) if path == "caf\u{FFFD}.o")
+        );
     }
-}
 
-impl<W: Write> AfterVariableAndValue<'_, W> {
-    pub fn end(self) -> Result<(), Error> {
-        self.0.write_build_end()
+    #[test]
+    fn reject_an_unknown_rule() {
+        let mut ninja_writer = validating_writer();
+        let command = Expr::new().text("cc -c ").variable("in");
+        ninja_writer.rule("cc").unwrap().command(command).unwrap().end().unwrap();
+        ninja_writer.build().unwrap().output("a.o").unwrap().rule("cc").unwrap().end().unwrap();
+        let result = ninja_writer.build().unwrap().output("b.o").unwrap().rule("c++");
+        assert!(
+            matches!(result, Err(Error(InnerError::UnknownRule { rule_name })) if rule_name == "c++")
+        );
+        // Nothing of the rejected build definition is written.
+        let expected: &[u8] = b"rule cc\n  command = cc -c $in\nbuild a.o: cc\n";
+        assert_eq!(ninja_writer.writer_mut().as_slice(), expected);
     }
-}
 
-impl<W: Write> AfterBuildRuleOrInput<'_, W> {
-    pub fn end(self) -> Result<(), Error> {
-        match self {
-            Self::AfterBuildRule(step) => step.end(),
-            Self::AfterInput(step) => step.end(),
-        }
+    #[test]
+    fn reject_a_duplicate_output() {
+        let mut ninja_writer = validating_writer();
+        ninja_writer.build().unwrap().output("all").unwrap().rule("phony").unwrap().end().unwrap();
+        let result = ninja_writer.build().unwrap().output("a").unwrap().implicit_output("all");
+        assert!(
+            matches!(result, Err(Error(InnerError::DuplicateOutput { output })) if output == "all")
+        );
+        assert_eq!(ninja_writer.writer_mut().as_slice(), b"build all: phony\n");
     }
-}
 
-impl<'a, W: Write> AfterInputOrImplicitDependency<'a, W> {
-    #[cfg(unix)]
-    pub fn unix_order_only_dependency(
-        self,
-        dependency: impl AsRef<OsStr>,
-    ) -> Result<AfterOrderOnlyDependency<'a, W>, Error> {
-        match self {
-            Self::AfterInput(step) => step.unix_order_only_dependency(dependency),
-            Self::AfterImplicitDependency(step) => step.unix_order_only_dependency(dependency),
-        }
+    #[test]
+    fn reject_a_cycle_across_files() {
+        let mut ninja_writer = validating_writer();
+        ninja_writer
+            .build()
+            .unwrap()
+            .output("a")
+            .unwrap()
+            .rule("phony")
+            .unwrap()
+            .input("b")
+            .unwrap()
+            .end()
+            .unwrap();
+        let mut subninja_writer = NinjaWriter::new(Config::with_width(80), Vec::new());
+        subninja_writer.enable_validation(ninja_writer.take_validation().unwrap());
+        subninja_writer
+            .build()
+            .unwrap()
+            .output("b")
+            .unwrap()
+            .rule("phony")
+            .unwrap()
+            .input("c")
+            .unwrap()
+            .end()
+            .unwrap();
+        subninja_writer
+            .build()
+            .unwrap()
+            .output("c")
+            .unwrap()
+            .rule("phony")
+            .unwrap()
+            .order_only_dependency("a")
+            .unwrap()
+            .end()
+            .unwrap();
+        let validation = subninja_writer.take_validation().unwrap();
+        let result = validation.check_cycles();
+        assert!(
+            matches!(result, Err(Error(InnerError::DependencyCycle { cycle })) if cycle == "a -> b -> c -> a")
+        );
+    }
+
+    #[test]
+    fn accept_a_graph_without_cycle() {
+        let mut ninja_writer = validating_writer();
+        ninja_writer
+            .build()
+            .unwrap()
+            .output("a")
+            .unwrap()
+            .rule("phony")
+            .unwrap()
+            .input("b")
+            .unwrap()
+            .input("c")
+            .unwrap()
+            .end()
+            .unwrap();
+        ninja_writer
+            .build()
+            .unwrap()
+            .output("b")
+            .unwrap()
+            .rule("phony")
+            .unwrap()
+            .input("c")
+            .unwrap()
+            .validation("a")
+            .unwrap()
+            .end()
+            .unwrap();
+        ninja_writer.take_validation().unwrap().check_cycles().unwrap();
     }
 }